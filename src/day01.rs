@@ -1,8 +1,10 @@
+use std::cmp::Ordering;
 use std::iter;
 use std::num::ParseIntError;
 
 use aoc_runner_derive::{aoc, aoc_generator};
-use itertools::izip;
+use colored::Colorize;
+use itertools::{izip, Itertools};
 
 #[aoc_generator(day1)]
 pub fn generator(input: &str) -> Result<Vec<u32>, ParseIntError> {
@@ -43,6 +45,118 @@ pub fn part2(input: &[u32]) -> usize {
     increases.count()
 }
 
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Flags each measurement that closes a three-measurement window deeper than
+/// the one before it, i.e. the positions counted by `part2`.
+pub fn increasing_windows(input: &[u32]) -> Vec<bool> {
+    // Consecutive windows share two measurements, so comparing the windows is
+    // the same as comparing the measurements that differ between them.
+    (0..input.len())
+        .map(|i| i >= 3 && input[i] > input[i - 3])
+        .collect()
+}
+
+/// Squashes the depths into at most `width` buckets, keeping the deepest
+/// reading of each bucket and whether any of its windows increased.
+fn downsample(input: &[u32], width: usize) -> Vec<(u32, bool)> {
+    if input.is_empty() || width == 0 {
+        return Vec::new();
+    }
+
+    let bucket_size = input.len().div_ceil(width);
+    let increases = increasing_windows(input);
+
+    input
+        .chunks(bucket_size)
+        .zip(increases.chunks(bucket_size))
+        .map(|(depths, increased)| {
+            (
+                *depths.iter().max().unwrap(),
+                increased.iter().any(|increased| *increased),
+            )
+        })
+        .collect()
+}
+
+/// Scales `depth` into `0..levels`, relative to the shallowest and deepest readings.
+fn level(depth: u32, min: u32, max: u32, levels: usize) -> usize {
+    if max == min {
+        0
+    } else {
+        ((depth - min) as u64 * (levels as u64 - 1) / (max - min) as u64) as usize
+    }
+}
+
+fn bounds(buckets: &[(u32, bool)]) -> (u32, u32) {
+    let (min, max) = buckets
+        .iter()
+        .map(|(depth, _)| *depth)
+        .minmax()
+        .into_option()
+        .unwrap_or((0, 0));
+
+    (min, max)
+}
+
+/// Renders the depth profile as a single line, at most `width` characters wide.
+/// Buckets containing an increasing window are highlighted.
+pub fn sparkline(input: &[u32], width: usize) -> String {
+    let buckets = downsample(input, width);
+    let (min, max) = bounds(&buckets);
+
+    buckets
+        .iter()
+        .map(|(depth, increased)| {
+            let spark = SPARKS[level(*depth, min, max, SPARKS.len())].to_string();
+
+            if *increased {
+                spark.green().bold().to_string()
+            } else {
+                spark.blue().to_string()
+            }
+        })
+        .collect()
+}
+
+/// Renders the depth profile as a chart `height` rows tall, with depth
+/// increasing down the page. A final row marks the columns containing
+/// windows counted by `part2`.
+pub fn chart(input: &[u32], width: usize, height: usize) -> String {
+    if height == 0 {
+        return String::new();
+    }
+
+    let buckets = downsample(input, width);
+    let (min, max) = bounds(&buckets);
+    let levels: Vec<usize> = buckets
+        .iter()
+        .map(|(depth, _)| level(*depth, min, max, height))
+        .collect();
+
+    let mut chart = String::new();
+
+    for row in 0..height {
+        for ((_, increased), level) in buckets.iter().zip(&levels) {
+            let cell = match row.cmp(level) {
+                Ordering::Less => " ".normal(),
+                Ordering::Equal if *increased => "*".green().bold(),
+                Ordering::Equal => "*".blue(),
+                Ordering::Greater => "░".dimmed(),
+            };
+            chart.push_str(&cell.to_string());
+        }
+        chart.push('\n');
+    }
+
+    for (_, increased) in &buckets {
+        chart.push(if *increased { '^' } else { ' ' });
+    }
+    chart.push('\n');
+
+    chart
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,4 +181,23 @@ mod tests {
     fn sample2() {
         assert_eq!(part2(&generator(SAMPLE).unwrap()), 5);
     }
+
+    #[test]
+    fn increasing_windows_match_part2() {
+        let input = generator(SAMPLE).unwrap();
+        let increases = increasing_windows(&input);
+
+        assert_eq!(increases.iter().filter(|i| **i).count(), part2(&input));
+    }
+
+    #[test]
+    fn render() {
+        colored::control::set_override(false);
+        let input = generator(SAMPLE).unwrap();
+
+        assert_eq!(sparkline(&input, 10), "▁▁▁▂▁▁▅█▇▇");
+        assert_eq!(sparkline(&input, 5), "▁▂▁█▇");
+        assert_eq!(chart(&input, 5, 4), "***  \n░░░  \n░░░ *\n░░░*░\n ^ ^^\n");
+        assert_eq!(chart(&[1, 5, 3], 3, 0), "");
+    }
 }
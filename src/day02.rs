@@ -1,19 +1,33 @@
+use std::collections::HashMap;
+use std::iter;
 use std::str::FromStr;
 
 use anyhow::{anyhow, Result};
 use aoc_runner_derive::{aoc, aoc_generator};
+use itertools::Itertools;
+use nalgebra::{Rotation3, Vector3};
+use nom::{
+    branch::alt,
+    character::complete::{char, multispace1, not_line_ending, satisfy, space1, u32},
+    combinator::{all_consuming, eof, not, recognize, value},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated},
+    Finish, IResult,
+};
 use strum::EnumString;
+use thiserror::Error;
 
-#[derive(Debug, PartialEq, EnumString)]
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
 #[strum(serialize_all = "lowercase")]
-enum Direction {
+pub enum Direction {
     Forward,
     Down,
     Up,
+    Back,
 }
 
-#[derive(Debug, PartialEq)]
-struct Vector(Direction, u32);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vector(pub Direction, pub u32);
 
 impl FromStr for Vector {
     type Err = anyhow::Error;
@@ -34,59 +48,966 @@ fn generator(input: &str) -> Result<Vec<Vector>> {
     input.lines().map(str::parse).collect()
 }
 
-struct Location {
-    horizontal: i64,
-    depth: i64,
+/// A model of how the submarine interprets each command of a course.
+pub trait Navigator {
+    fn apply(&mut self, vector: &Vector);
+
+    fn location(&self) -> Location;
+}
+
+/// Steers `navigator` through every command of `course`, in order.
+pub fn navigate<N: Navigator>(mut navigator: N, course: &[Vector]) -> N {
+    for vector in course {
+        navigator.apply(vector);
+    }
+
+    navigator
+}
+
+/// Records the state of `navigator` before the course, and after every command of it.
+pub fn trajectory<N: Navigator + Clone>(navigator: N, course: &[Vector]) -> Vec<N> {
+    let states = course.iter().scan(navigator.clone(), |navigator, vector| {
+        navigator.apply(vector);
+        Some(navigator.clone())
+    });
+
+    iter::once(navigator).chain(states).collect()
+}
+
+/// Draws a trajectory as an SVG polyline, with depth increasing down the image
+/// and the surface marked as a horizontal line.
+pub fn svg<N: Navigator>(trajectory: &[N]) -> String {
+    let locations: Vec<Location> = trajectory.iter().map(Navigator::location).collect();
+
+    let (min_x, max_x) = locations
+        .iter()
+        .map(|location| location.horizontal)
+        .chain(iter::once(0))
+        .minmax()
+        .into_option()
+        .unwrap();
+    let (min_y, max_y) = locations
+        .iter()
+        .map(|location| location.depth)
+        .chain(iter::once(0))
+        .minmax()
+        .into_option()
+        .unwrap();
+    let (width, height) = ((max_x - min_x).max(1), (max_y - min_y).max(1));
+    let margin = width.max(height) / 20 + 1;
+
+    let points = locations
+        .iter()
+        .map(|location| format!("{},{}", location.horizontal, location.depth))
+        .join(" ");
+
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">
+  <line x1="{}" y1="0" x2="{}" y2="0" stroke="lightblue" vector-effect="non-scaling-stroke"/>
+  <polyline points="{}" fill="none" stroke="navy" vector-effect="non-scaling-stroke"/>
+</svg>
+"#,
+        min_x - margin,
+        min_y - margin,
+        width + 2 * margin,
+        height + 2 * margin,
+        min_x - margin,
+        max_x + margin,
+        points
+    )
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub horizontal: i64,
+    pub depth: i64,
+}
+
+impl Location {
+    pub fn product(&self) -> i64 {
+        self.horizontal * self.depth
+    }
+}
+
+impl Navigator for Location {
+    fn apply(&mut self, Vector(direction, magnitude): &Vector) {
+        match direction {
+            Direction::Forward => self.horizontal += *magnitude as i64,
+            Direction::Up => self.depth -= *magnitude as i64,
+            Direction::Down => self.depth += *magnitude as i64,
+            Direction::Back => self.horizontal -= *magnitude as i64,
+        }
+    }
+
+    fn location(&self) -> Location {
+        *self
+    }
 }
 
 #[aoc(day2, part1)]
 fn part1(input: &[Vector]) -> i64 {
-    let mut location = Location {
-        horizontal: 0,
-        depth: 0,
-    };
+    navigate(Location::default(), input).location().product()
+}
 
-    for Vector(direction, magnitude) in input {
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OrientedLocation {
+    pub horizontal: i64,
+    pub depth: i64,
+    pub aim: i64,
+}
+
+impl Navigator for OrientedLocation {
+    fn apply(&mut self, Vector(direction, magnitude): &Vector) {
         match direction {
-            Direction::Forward => location.horizontal += *magnitude as i64,
-            Direction::Up => location.depth -= *magnitude as i64,
-            Direction::Down => location.depth += *magnitude as i64,
+            Direction::Down => self.aim += *magnitude as i64,
+            Direction::Up => self.aim -= *magnitude as i64,
+            Direction::Forward => {
+                self.horizontal += *magnitude as i64;
+                self.depth += self.aim * (*magnitude as i64)
+            }
+            Direction::Back => {
+                self.horizontal -= *magnitude as i64;
+                self.depth -= self.aim * (*magnitude as i64)
+            }
         }
     }
 
-    location.horizontal * location.depth
+    fn location(&self) -> Location {
+        Location {
+            horizontal: self.horizontal,
+            depth: self.depth,
+        }
+    }
+}
+
+#[aoc(day2, part2)]
+fn part2(input: &[Vector]) -> i64 {
+    navigate(OrientedLocation::default(), input)
+        .location()
+        .product()
+}
+
+/// Commands are numbered from 1, as lines of the course.
+#[derive(Error, Debug, PartialEq)]
+pub enum CourseError {
+    #[error("Command {command} takes the submarine above the surface, to depth {depth}")]
+    SurfaceBreach { command: usize, depth: i64 },
+    #[error(
+        "Command {command} takes the submarine to depth {depth}, beyond the maximum of {max_depth}"
+    )]
+    TooDeep {
+        command: usize,
+        depth: i64,
+        max_depth: i64,
+    },
+    #[error("Command {command} overflows the submarine's position")]
+    Overflow { command: usize },
+}
+
+/// A navigation model that can detect arithmetic overflow.
+pub trait CheckedNavigator: Navigator + Sized {
+    /// Returns the state after `vector`, or `None` if it cannot be represented.
+    fn checked_apply(&self, vector: &Vector) -> Option<Self>;
+}
+
+impl CheckedNavigator for Location {
+    fn checked_apply(&self, Vector(direction, magnitude): &Vector) -> Option<Self> {
+        let magnitude = *magnitude as i64;
+
+        Some(match direction {
+            Direction::Forward => Location {
+                horizontal: self.horizontal.checked_add(magnitude)?,
+                ..*self
+            },
+            Direction::Back => Location {
+                horizontal: self.horizontal.checked_sub(magnitude)?,
+                ..*self
+            },
+            Direction::Up => Location {
+                depth: self.depth.checked_sub(magnitude)?,
+                ..*self
+            },
+            Direction::Down => Location {
+                depth: self.depth.checked_add(magnitude)?,
+                ..*self
+            },
+        })
+    }
+}
+
+impl CheckedNavigator for OrientedLocation {
+    fn checked_apply(&self, Vector(direction, magnitude): &Vector) -> Option<Self> {
+        let magnitude = *magnitude as i64;
+
+        Some(match direction {
+            Direction::Down => OrientedLocation {
+                aim: self.aim.checked_add(magnitude)?,
+                ..*self
+            },
+            Direction::Up => OrientedLocation {
+                aim: self.aim.checked_sub(magnitude)?,
+                ..*self
+            },
+            Direction::Forward => OrientedLocation {
+                horizontal: self.horizontal.checked_add(magnitude)?,
+                depth: self.depth.checked_add(self.aim.checked_mul(magnitude)?)?,
+                ..*self
+            },
+            Direction::Back => OrientedLocation {
+                horizontal: self.horizontal.checked_sub(magnitude)?,
+                depth: self.depth.checked_sub(self.aim.checked_mul(magnitude)?)?,
+                ..*self
+            },
+        })
+    }
+}
+
+/// Steers `navigator` through `course` like `navigate`, but stops at the first
+/// command that surfaces the submarine, takes it deeper than `max_depth`, or overflows.
+///
+/// Depth only changes linearly within a command, so checking after each one is enough.
+pub fn navigate_checked<N: CheckedNavigator>(
+    navigator: N,
+    course: &[Vector],
+    max_depth: Option<i64>,
+) -> Result<N, CourseError> {
+    course
+        .iter()
+        .enumerate()
+        .try_fold(navigator, |navigator, (index, vector)| {
+            let command = index + 1;
+            let navigator = navigator
+                .checked_apply(vector)
+                .ok_or(CourseError::Overflow { command })?;
+            let depth = navigator.location().depth;
+
+            match max_depth {
+                _ if depth < 0 => Err(CourseError::SurfaceBreach { command, depth }),
+                Some(max_depth) if depth > max_depth => Err(CourseError::TooDeep {
+                    command,
+                    depth,
+                    max_depth,
+                }),
+                _ => Ok(navigator),
+            }
+        })
+}
+
+/// The navigation models of part 1 and part 2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Plain,
+    Aimed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    FewestCommands,
+    LeastMagnitude,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Constraints {
+    pub submerged: bool,
+    pub max_depth: Option<i64>,
+}
+
+impl Constraints {
+    fn allow(&self, location: &Location) -> bool {
+        !(self.submerged && location.depth < 0
+            || matches!(self.max_depth, Some(max_depth) if location.depth > max_depth))
+    }
+}
+
+fn magnitude(magnitude: i64) -> Option<u32> {
+    u32::try_from(magnitude.unsigned_abs()).ok()
+}
+
+fn horizontal(horizontal: i64) -> Option<Vector> {
+    let direction = if horizontal < 0 {
+        Direction::Back
+    } else {
+        Direction::Forward
+    };
+
+    Some(Vector(direction, magnitude(horizontal)?))
+}
+
+fn vertical(vertical: i64) -> Option<Vector> {
+    let direction = if vertical < 0 {
+        Direction::Up
+    } else {
+        Direction::Down
+    };
+
+    Some(Vector(direction, magnitude(vertical)?))
+}
+
+/// Every positive divisor of `n`, which must be positive, in no particular order.
+fn divisors(n: i64) -> Vec<i64> {
+    let mut divisors = vec![1];
+    let mut rest = n;
+    let mut factor = 2;
+
+    while factor <= rest / factor {
+        let smaller = divisors.len();
+        let mut power = 1;
+
+        while rest % factor == 0 {
+            rest /= factor;
+            power *= factor;
+            divisors.extend(
+                (0..smaller)
+                    .map(|index| divisors[index] * power)
+                    .collect_vec(),
+            );
+        }
+
+        factor += 1;
+    }
+
+    if rest > 1 {
+        divisors.extend(divisors.iter().map(|divisor| divisor * rest).collect_vec());
+    }
+
+    divisors
+}
+
+/// Commands that reach `depth` (which must not be negative) from the surface
+/// in as few commands as possible, under the aim model.
+fn fewest_aimed(horizontal_target: i64, depth: i64) -> Option<Vec<Vector>> {
+    // One command can't change the depth, and two can only do so with a single
+    // aim held over the whole horizontal distance. Three always suffice.
+    if depth == 0 {
+        Some(vec![horizontal(horizontal_target)?])
+    } else if horizontal_target != 0 && depth % horizontal_target == 0 {
+        Some(vec![
+            vertical(depth / horizontal_target)?,
+            horizontal(horizontal_target)?,
+        ])
+    } else if horizontal_target == 0 {
+        // Go forward at no aim, then back up the same distance with the nose raised.
+        let distance = divisors(depth)
+            .into_iter()
+            .filter(|distance| {
+                magnitude(*distance).is_some() && magnitude(depth / distance).is_some()
+            })
+            .min_by_key(|distance| (2 * distance + depth / distance, *distance))?;
+
+        Some(vec![
+            horizontal(distance)?,
+            vertical(-depth / distance)?,
+            horizontal(-distance)?,
+        ])
+    } else {
+        // Cover the part of the distance that doesn't divide the depth at no aim.
+        let distance = divisors(depth)
+            .into_iter()
+            .filter(|distance| distance.unsigned_abs() < horizontal_target.unsigned_abs())
+            .max()?
+            * horizontal_target.signum();
+
+        Some(vec![
+            horizontal(horizontal_target - distance)?,
+            vertical(depth / distance)?,
+            horizontal(distance)?,
+        ])
+    }
 }
 
-struct OrientedLocation {
-    horizontal: i64,
+/// The aims `high` and `low` minimising `high_cost * high + low_cost * low`
+/// for which `forward * high + back * low` reaches `depth`, if that cost is
+/// below `bound`.
+///
+/// Raising `high` by `back` while lowering `low` by `forward` keeps the depth,
+/// so one of them can always be kept below the other's unit count, on the side
+/// that shift makes cheaper. Costs within that range only grow from the
+/// continuous optimum, so the search stops once they can't beat the best.
+fn cheapest_aim(
+    forward: i64,
+    back: i64,
     depth: i64,
-    aim: i64,
+    high_cost: i64,
+    low_cost: i64,
+    bound: i64,
+) -> Option<(i64, i64, i64)> {
+    let ceil_div = |numerator: i64, denominator: i64| {
+        let numerator = numerator.max(0);
+        numerator / denominator + i64::from(numerator % denominator != 0)
+    };
+
+    let candidates: Vec<(i64, i64)> = match (forward, back) {
+        (0, 0) => vec![],
+        (_, 0) => vec![(ceil_div(depth, forward), 0)],
+        (0, _) => vec![(0, ceil_div(depth, back))],
+        _ if high_cost * back <= low_cost * forward => {
+            let mut candidates = Vec::new();
+            let mut best = bound;
+
+            for low in 0..forward.min(ceil_div(depth, back) + 1) {
+                if high_cost * (depth - back * low) + low_cost * low * forward
+                    >= best.saturating_mul(forward)
+                {
+                    break;
+                }
+
+                let high = ceil_div(depth - back * low, forward);
+                best = best.min(high_cost * high + low_cost * low);
+                candidates.push((high, low));
+            }
+
+            candidates
+        }
+        _ => {
+            let mut candidates = Vec::new();
+            let mut best = bound;
+
+            for high in 0..back.min(ceil_div(depth, forward) + 1) {
+                if low_cost * (depth - forward * high) + high_cost * high * back
+                    >= best.saturating_mul(back)
+                {
+                    break;
+                }
+
+                let low = ceil_div(depth - forward * high, back);
+                best = best.min(high_cost * high + low_cost * low);
+                candidates.push((high, low));
+            }
+
+            candidates
+        }
+    };
+
+    candidates
+        .into_iter()
+        .map(|(high, low)| (high_cost * high + low_cost * low, high, low))
+        .filter(|(cost, ..)| *cost < bound)
+        .min()
 }
 
-#[aoc(day2, part2)]
-fn part2(input: &[Vector]) -> i64 {
-    let mut location = OrientedLocation {
-        horizontal: 0,
-        depth: 0,
-        aim: 0,
+/// Commands that reach `depth` (which must not be negative) from the surface
+/// with the least total magnitude, under the aim model.
+///
+/// Every unit of magnitude is one unit of travel or of aim. A plan that moves
+/// `forward` units forwards and `back` units backwards, with its aim ranging
+/// from `-low` to `high`, can reach at most `forward * high + back * low`, and
+/// must spend at least `min(2 * high + low, high + 2 * low)` on aim to visit
+/// both extremes. This searches for the cheapest such shape, then lowers the
+/// aim of some units to hit the depth exactly.
+fn least_magnitude_aimed(horizontal_target: i64, depth: i64) -> Option<Vec<Vector>> {
+    if depth == 0 {
+        return Some(vec![horizontal(horizontal_target)?]);
+    }
+
+    // Targets beyond a single command's reach aren't searched, which keeps the
+    // search's sums in range.
+    magnitude(horizontal_target)?;
+
+    let mut best: Option<(i64, i64, i64, i64, i64)> = None;
+
+    for back in (-horizontal_target).max(0).. {
+        let forward = back + horizontal_target;
+        let bound = best.map_or(i64::MAX, |(cost, ..)| cost - forward - back);
+
+        if bound <= 0 {
+            break;
+        }
+
+        // Holding the aim nearer one extreme costs one unit per unit of aim,
+        // and visiting the other costs two.
+        let aims = [(2, 1), (1, 2)]
+            .into_iter()
+            .filter_map(|(high_cost, low_cost)| {
+                cheapest_aim(forward, back, depth, high_cost, low_cost, bound)
+            })
+            .min();
+
+        if let Some((aim_cost, high, low)) = aims {
+            best = Some((forward + back + aim_cost, forward, back, high, low));
+        }
+    }
+
+    let (_, forward, back, high, low) = best?;
+
+    // Spread any excess depth over the forward units first, then the back units.
+    let excess = forward * high + back * low - depth;
+    let forward_excess = excess.min(forward * high);
+    let back_excess = excess - forward_excess;
+
+    let spread = |units: i64, top: i64, excess: i64| {
+        if units == 0 {
+            return [(top, 0), (top, 0)];
+        }
+
+        let (lowered, remainder) = (excess / units, excess % units);
+        [
+            (top - lowered - 1, remainder),
+            (top - lowered, units - remainder),
+        ]
+    };
+
+    let forwards = spread(forward, high, forward_excess);
+    let backs = spread(back, low, back_excess).map(|(aim, units)| (-aim, -units));
+
+    // Every unit adds to the depth, so the plan never rises above the surface
+    // or dives below the target.
+    let segments = if high <= low {
+        [forwards, backs].concat()
+    } else {
+        [backs, forwards].concat()
     };
 
-    for Vector(direction, magnitude) in input {
+    let mut aim = 0;
+    let mut course = Vec::new();
+
+    for (segment_aim, units) in segments {
+        if units == 0 {
+            continue;
+        }
+
+        if segment_aim != aim {
+            course.push(vertical(segment_aim - aim)?);
+            aim = segment_aim;
+        }
+
+        course.push(horizontal(units)?);
+    }
+
+    Some(course)
+}
+
+/// Synthesises a course from the surface to `target` under `model`, or `None`
+/// if no course satisfies the constraints.
+///
+/// The planned courses change depth monotonically, so they never go above the
+/// surface or below the target unless they have to.
+pub fn plan(
+    model: Model,
+    target: Location,
+    objective: Objective,
+    constraints: &Constraints,
+) -> Option<Vec<Vector>> {
+    if !constraints.allow(&target) || !constraints.allow(&Location::default()) {
+        return None;
+    }
+
+    let course = match model {
+        Model::Plain => vec![vertical(target.depth)?, horizontal(target.horizontal)?],
+        Model::Aimed => {
+            // Plans for negative depths are plans for positive depths, upside down
+            let depth = i64::try_from(target.depth.unsigned_abs()).ok()?;
+            let course = match objective {
+                Objective::FewestCommands => fewest_aimed(target.horizontal, depth),
+                Objective::LeastMagnitude => least_magnitude_aimed(target.horizontal, depth),
+            }?;
+
+            if target.depth < 0 {
+                course
+                    .into_iter()
+                    .map(|Vector(direction, magnitude)| match direction {
+                        Direction::Up => Vector(Direction::Down, magnitude),
+                        Direction::Down => Vector(Direction::Up, magnitude),
+                        _ => Vector(direction, magnitude),
+                    })
+                    .collect()
+            } else {
+                course
+            }
+        }
+    };
+
+    Some(
+        course
+            .into_iter()
+            .filter(|Vector(_, magnitude)| *magnitude != 0)
+            .collect(),
+    )
+}
+
+/// Replays `course` under `model`, checking that it ends at `target`
+/// without breaking any of the constraints along the way.
+pub fn verify(
+    model: Model,
+    course: &[Vector],
+    target: Location,
+    constraints: &Constraints,
+) -> bool {
+    let locations: Vec<Location> = match model {
+        Model::Plain => trajectory(Location::default(), course)
+            .iter()
+            .map(Navigator::location)
+            .collect(),
+        Model::Aimed => trajectory(OrientedLocation::default(), course)
+            .iter()
+            .map(Navigator::location)
+            .collect(),
+    };
+
+    locations.last() == Some(&target)
+        && locations.iter().all(|location| constraints.allow(location))
+}
+
+fn is_horizontal(direction: &Direction) -> bool {
+    matches!(direction, Direction::Forward | Direction::Back)
+}
+
+fn signed(Vector(direction, magnitude): &Vector) -> i64 {
+    match direction {
+        Direction::Forward | Direction::Down => *magnitude as i64,
+        Direction::Back | Direction::Up => -(*magnitude as i64),
+    }
+}
+
+/// Adds `vector` to `course`, merging it into the last command if they are
+/// along the same axis, and dropping commands that cancel out.
+///
+/// Under the aim model, two adjacent horizontal commands are steered at the same
+/// aim, and two adjacent vertical commands are not separated by any travel, so
+/// merging them never changes the final state.
+fn push_merged(course: &mut Vec<Vector>, vector: Vector) {
+    let merged = match course.last() {
+        Some(last) if is_horizontal(&last.0) == is_horizontal(&vector.0) => {
+            let net = signed(last) + signed(&vector);
+
+            if is_horizontal(&vector.0) {
+                horizontal(net)
+            } else {
+                vertical(net)
+            }
+        }
+        _ => None,
+    };
+
+    match merged {
+        Some(merged) => {
+            course.pop();
+
+            if merged.1 != 0 {
+                course.push(merged);
+            }
+        }
+        None if vector.1 != 0 => course.push(vector),
+        None => {}
+    }
+}
+
+/// Shortens `course` without changing where it leaves the submarine under
+/// `model`, including its aim under the aim model.
+pub fn optimise(model: Model, course: &[Vector]) -> Vec<Vector> {
+    let mut optimised = Vec::new();
+
+    match model {
+        Model::Plain => {
+            // Horizontal and vertical commands commute, so gather each axis into one
+            let (horizontals, verticals): (Vec<&Vector>, Vec<&Vector>) =
+                course.iter().partition(|vector| is_horizontal(&vector.0));
+
+            for vector in horizontals.into_iter().chain(verticals) {
+                push_merged(&mut optimised, *vector);
+            }
+        }
+        Model::Aimed => {
+            for vector in course {
+                push_merged(&mut optimised, *vector);
+            }
+        }
+    }
+
+    optimised
+}
+
+/// A command for the three-dimensional submarine. Turns are in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manoeuvre {
+    Move(Vector),
+    Left(u32),
+    Right(u32),
+    PitchUp(u32),
+    PitchDown(u32),
+}
+
+impl FromStr for Manoeuvre {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words[..] {
+            ["left", degrees] => Ok(Manoeuvre::Left(degrees.parse()?)),
+            ["right", degrees] => Ok(Manoeuvre::Right(degrees.parse()?)),
+            ["pitch", "up", degrees] => Ok(Manoeuvre::PitchUp(degrees.parse()?)),
+            ["pitch", "down", degrees] => Ok(Manoeuvre::PitchDown(degrees.parse()?)),
+            _ => Ok(Manoeuvre::Move(s.parse()?)),
+        }
+    }
+}
+
+// Steeper pitches would leave the submarine unable to make horizontal progress.
+const MAX_PITCH: f64 = 89.0;
+
+/// A submarine free to turn, with `x` pointing along its initial heading,
+/// `y` to its right and `z` down.
+///
+/// The heading always has a horizontal length of one, and its `z` component is
+/// the aim from part 2, so a course of classic commands is steered exactly as
+/// by `OrientedLocation`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submarine {
+    pub position: Vector3<f64>,
+    pub heading: Vector3<f64>,
+    pub distance: f64,
+}
+
+impl Default for Submarine {
+    fn default() -> Self {
+        Submarine {
+            position: Vector3::zeros(),
+            heading: Vector3::x(),
+            distance: 0.0,
+        }
+    }
+}
+
+impl Submarine {
+    pub fn steer(&mut self, manoeuvre: &Manoeuvre) {
+        match manoeuvre {
+            Manoeuvre::Move(vector) => self.apply(vector),
+            Manoeuvre::Left(degrees) => self.yaw(-(*degrees as f64)),
+            Manoeuvre::Right(degrees) => self.yaw(*degrees as f64),
+            Manoeuvre::PitchUp(degrees) => self.pitch(-(*degrees as f64)),
+            Manoeuvre::PitchDown(degrees) => self.pitch(*degrees as f64),
+        }
+    }
+
+    fn yaw(&mut self, degrees: f64) {
+        self.heading =
+            Rotation3::from_axis_angle(&Vector3::z_axis(), degrees.to_radians()) * self.heading;
+    }
+
+    fn pitch(&mut self, degrees: f64) {
+        let current = self.heading.z.atan().to_degrees();
+        let pitch = (current + degrees).clamp(-MAX_PITCH, MAX_PITCH);
+
+        // Classic `down` and `up` commands can aim past the limit, and pitching
+        // further the same way shouldn't pull the heading back to it.
+        if (pitch - current) * degrees > 0.0 {
+            self.heading.z = pitch.to_radians().tan();
+        }
+    }
+
+    fn travel(&mut self, magnitude: f64) {
+        let displacement = self.heading * magnitude;
+
+        self.position += displacement;
+        self.distance += displacement.norm();
+    }
+
+    /// The horizontal distance from the start, in Manhattan metric, times the depth.
+    pub fn manhattan_product(&self) -> f64 {
+        (self.position.x.abs() + self.position.y.abs()) * self.position.z
+    }
+}
+
+impl Navigator for Submarine {
+    fn apply(&mut self, Vector(direction, magnitude): &Vector) {
         match direction {
-            Direction::Down => location.aim += *magnitude as i64,
-            Direction::Up => location.aim -= *magnitude as i64,
-            Direction::Forward => {
-                location.horizontal += *magnitude as i64;
-                location.depth += location.aim * (*magnitude as i64)
+            Direction::Down => self.heading.z += *magnitude as f64,
+            Direction::Up => self.heading.z -= *magnitude as f64,
+            Direction::Forward => self.travel(*magnitude as f64),
+            Direction::Back => self.travel(-(*magnitude as f64)),
+        }
+    }
+
+    fn location(&self) -> Location {
+        Location {
+            horizontal: (self.position.x.abs() + self.position.y.abs()).round() as i64,
+            depth: self.position.z.round() as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Command(Vector),
+    Surface,
+    Repeat(u32, Vec<Statement>),
+    Macro(String, Vec<Statement>),
+    Call(String),
+}
+
+/// A mission script: commands, `# comments`, `repeat N { ... }` blocks,
+/// `macro name { ... }` definitions and calls to previously defined macros.
+///
+/// Macro names are made of letters and underscores, and can't be keywords.
+/// Numbers and names must be followed by a space, a brace, a comment or the
+/// end of the script.
+///
+/// `surface` rises by the depth reached so far under the part 1 model, so
+/// under the aim model it levels the aim out instead of rising.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script(Vec<Statement>);
+
+fn comment(input: &str) -> IResult<&str, &str> {
+    recognize(pair(char('#'), not_line_ending))(input)
+}
+
+fn blank(input: &str) -> IResult<&str, ()> {
+    value((), many0(alt((multispace1, comment))))(input)
+}
+
+const KEYWORDS: &[&str] = &[
+    "forward", "down", "up", "back", "surface", "repeat", "macro",
+];
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Numbers and names must not run straight into another word
+fn word_end(input: &str) -> IResult<&str, ()> {
+    not(satisfy(is_word))(input)
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    terminated(
+        recognize(pair(
+            satisfy(|c| c.is_alphabetic()),
+            many0(satisfy(|c| c.is_alphabetic() || c == '_')),
+        )),
+        word_end,
+    )(input)
+}
+
+fn macro_name(input: &str) -> IResult<&str, &str> {
+    nom::combinator::verify(identifier, |name: &str| !KEYWORDS.contains(&name))(input)
+}
+
+fn number(input: &str) -> IResult<&str, u32> {
+    terminated(u32, word_end)(input)
+}
+
+fn block(input: &str) -> IResult<&str, Vec<Statement>> {
+    delimited(
+        pair(char('{'), blank),
+        many0(terminated(statement, blank)),
+        char('}'),
+    )(input)
+}
+
+fn statement(input: &str) -> IResult<&str, Statement> {
+    let (rest, word) = identifier(input)?;
+
+    match word {
+        "forward" | "down" | "up" | "back" => {
+            let (rest, magnitude) = preceded(space1, number)(rest)?;
+            // The keyword has already been matched, so this cannot fail
+            let direction = word.parse().unwrap();
+            Ok((rest, Statement::Command(Vector(direction, magnitude))))
+        }
+        "surface" => Ok((rest, Statement::Surface)),
+        "repeat" => {
+            let (rest, (count, body)) = pair(delimited(space1, number, blank), block)(rest)?;
+            Ok((rest, Statement::Repeat(count, body)))
+        }
+        "macro" => {
+            let (rest, (name, body)) = pair(delimited(space1, macro_name, blank), block)(rest)?;
+            Ok((rest, Statement::Macro(name.to_owned(), body)))
+        }
+        name => Ok((rest, Statement::Call(name.to_owned()))),
+    }
+}
+
+impl FromStr for Script {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all_consuming(delimited(blank, many0(terminated(statement, blank)), eof))(s)
+            .finish()
+            .map(|(_, statements)| Script(statements))
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to parse script at '{}'",
+                    e.input.lines().next().unwrap_or_default()
+                )
+            })
+    }
+}
+
+#[derive(Default)]
+struct Expansion<'a> {
+    macros: HashMap<&'a str, &'a [Statement]>,
+    calls: Vec<&'a str>,
+    course: Vec<Vector>,
+    // `surface` needs to know how far down the submarine is,
+    // which is tracked as in part 1.
+    location: Location,
+}
+
+impl<'a> Expansion<'a> {
+    fn push(&mut self, vector: Vector) {
+        self.location.apply(&vector);
+        self.course.push(vector);
+    }
+
+    fn expand(&mut self, statements: &'a [Statement]) -> Result<()> {
+        for statement in statements {
+            match statement {
+                Statement::Command(vector) => self.push(*vector),
+                Statement::Surface => {
+                    if self.location.depth > 0 {
+                        let depth = u32::try_from(self.location.depth).map_err(|_| {
+                            anyhow!(
+                                "Unable to surface from depth {} in one command",
+                                self.location.depth
+                            )
+                        })?;
+                        self.push(Vector(Direction::Up, depth));
+                    }
+                }
+                Statement::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        self.expand(body)?;
+                    }
+                }
+                Statement::Macro(name, body) => {
+                    self.macros.insert(name, body);
+                }
+                Statement::Call(name) => {
+                    if self.calls.contains(&name.as_str()) {
+                        return Err(anyhow!("Macro '{}' calls itself", name));
+                    }
+
+                    let body = *self
+                        .macros
+                        .get(name.as_str())
+                        .ok_or_else(|| anyhow!("Macro '{}' is not defined", name))?;
+
+                    self.calls.push(name);
+                    self.expand(body)?;
+                    self.calls.pop();
+                }
             }
         }
+
+        Ok(())
     }
+}
+
+impl Script {
+    /// Expands repeats, macros and `surface` into a plain course.
+    /// Macros must be defined before they are called.
+    pub fn expand(&self) -> Result<Vec<Vector>> {
+        let mut expansion = Expansion::default();
+        expansion.expand(&self.0)?;
 
-    location.horizontal * location.depth
+        Ok(expansion.course)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     const SAMPLE: &str = "forward 5
@@ -105,4 +1026,339 @@ forward 2";
     fn sample2() {
         assert_eq!(part2(&generator(SAMPLE).unwrap()), 900);
     }
+
+    #[derive(Default)]
+    struct Drifting {
+        location: Location,
+        current: i64,
+    }
+
+    impl Navigator for Drifting {
+        fn apply(&mut self, vector: &Vector) {
+            self.location.apply(vector);
+            self.location.horizontal += self.current;
+        }
+
+        fn location(&self) -> Location {
+            self.location
+        }
+    }
+
+    #[test]
+    fn custom_navigator() {
+        let drifting = Drifting {
+            current: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            navigate(drifting, &generator(SAMPLE).unwrap()).location(),
+            Location {
+                horizontal: 21,
+                depth: 10
+            }
+        );
+    }
+
+    #[test]
+    fn script() {
+        let script: Script = "# sample course, in a roundabout way
+macro dive {
+    down 5 # comment after a command
+}
+
+repeat 2 { forward 5 dive }
+back 2
+up 3
+down 8
+forward 2
+surface"
+            .parse()
+            .unwrap();
+
+        let course = script.expand().unwrap();
+
+        assert_eq!(course.len(), 9);
+        assert_eq!(course.last(), Some(&Vector(Direction::Up, 15)));
+        assert_eq!(
+            navigate(Location::default(), &course[..8])
+                .location()
+                .product(),
+            150
+        );
+    }
+
+    #[test]
+    fn script_errors() {
+        assert!("forward".parse::<Script>().is_err());
+        assert!("repeat 2 { up 1".parse::<Script>().is_err());
+        assert!("forward 5dive".parse::<Script>().is_err());
+        assert!("forward5dive".parse::<Script>().is_err());
+        assert!("macro dive2 { up 1 }".parse::<Script>().is_err());
+        assert!("macro forward { up 1 }".parse::<Script>().is_err());
+        assert!("macro repeat { up 1 }".parse::<Script>().is_err());
+        assert!("repeat 2{ up 1 }#twice\nsurface".parse::<Script>().is_ok());
+        assert!("dive".parse::<Script>().unwrap().expand().is_err());
+        assert!("macro dive { dive } dive"
+            .parse::<Script>()
+            .unwrap()
+            .expand()
+            .is_err());
+        assert!("repeat 2 { down 4294967295 } surface"
+            .parse::<Script>()
+            .unwrap()
+            .expand()
+            .is_err());
+    }
+
+    #[test]
+    fn trajectories() {
+        let course = generator(SAMPLE).unwrap();
+
+        let plain = trajectory(Location::default(), &course);
+        assert_eq!(plain.len(), course.len() + 1);
+        assert_eq!(plain.last().unwrap().product(), part1(&course));
+
+        let oriented = trajectory(OrientedLocation::default(), &course);
+        assert_eq!(
+            oriented[3],
+            OrientedLocation {
+                horizontal: 13,
+                depth: 40,
+                aim: 5
+            }
+        );
+        assert_eq!(
+            oriented.last().unwrap().location().product(),
+            part2(&course)
+        );
+
+        assert!(svg(&oriented).contains(r#"points="0,0 5,0 5,0 13,40 13,40 13,40 15,60""#));
+    }
+
+    #[test]
+    fn submarine_classic() {
+        let course = generator(SAMPLE).unwrap();
+        let submarine = navigate(Submarine::default(), &course);
+
+        assert_eq!(submarine.manhattan_product(), part2(&course) as f64);
+        assert_eq!(submarine.location().product(), part2(&course));
+    }
+
+    #[test]
+    fn submarine_manoeuvres() {
+        let mut submarine = Submarine::default();
+
+        for manoeuvre in [
+            "forward 3",
+            "right 90",
+            "pitch down 45",
+            "forward 4",
+            "left 90",
+            "forward 2",
+        ] {
+            submarine.steer(&manoeuvre.parse().unwrap());
+        }
+
+        assert!((submarine.position - Vector3::new(5.0, 4.0, 6.0)).norm() < 1e-9);
+        assert!((submarine.distance - (3.0 + 6.0 * 2f64.sqrt())).abs() < 1e-9);
+        assert!((submarine.manhattan_product() - 54.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn submarine_pitch_after_steep_dive() {
+        let mut submarine = Submarine::default();
+
+        submarine.steer(&"down 100".parse().unwrap());
+        submarine.steer(&"pitch down 1".parse().unwrap());
+        assert_eq!(submarine.heading.z, 100.0);
+
+        submarine.steer(&"pitch up 1".parse().unwrap());
+        let pitched = (100f64.atan() - 1f64.to_radians()).tan();
+        assert!((submarine.heading.z - pitched).abs() < 1e-9);
+
+        submarine.steer(&"up 200".parse().unwrap());
+        submarine.steer(&"pitch up 5".parse().unwrap());
+        assert!(submarine.heading.z < -71.0);
+    }
+
+    #[test]
+    fn checked() {
+        let course = generator(SAMPLE).unwrap();
+
+        assert_eq!(
+            navigate_checked(OrientedLocation::default(), &course, None),
+            Ok(navigate(OrientedLocation::default(), &course))
+        );
+        assert_eq!(
+            navigate_checked(OrientedLocation::default(), &course, Some(50)),
+            Err(CourseError::TooDeep {
+                command: 6,
+                depth: 60,
+                max_depth: 50
+            })
+        );
+        let breach = navigate_checked(
+            Location::default(),
+            &generator("down 2\nup 3").unwrap(),
+            None,
+        );
+        assert_eq!(
+            breach,
+            Err(CourseError::SurfaceBreach {
+                command: 2,
+                depth: -1
+            })
+        );
+        assert_eq!(
+            breach.unwrap_err().to_string(),
+            "Command 2 takes the submarine above the surface, to depth -1"
+        );
+
+        let overflowing = [
+            Vector(Direction::Down, u32::MAX),
+            Vector(Direction::Forward, u32::MAX),
+        ];
+        assert_eq!(
+            navigate_checked(OrientedLocation::default(), &overflowing, None),
+            Err(CourseError::Overflow { command: 2 })
+        );
+    }
+
+    #[test]
+    fn planner() {
+        let submerged = Constraints {
+            submerged: true,
+            max_depth: None,
+        };
+
+        for (horizontal, depth) in [(15, 60), (15, 61), (0, 12), (-7, 30), (1, 190), (13, -5)] {
+            let target = Location { horizontal, depth };
+
+            for model in [Model::Plain, Model::Aimed] {
+                for objective in [Objective::FewestCommands, Objective::LeastMagnitude] {
+                    let course = plan(model, target, objective, &Constraints::default()).unwrap();
+                    assert!(verify(model, &course, target, &Constraints::default()));
+
+                    let course = plan(model, target, objective, &submerged);
+                    assert_eq!(course.is_some(), depth >= 0);
+                    if let Some(course) = course {
+                        assert!(verify(model, &course, target, &submerged));
+                    }
+                }
+            }
+        }
+
+        let target = Location {
+            horizontal: 15,
+            depth: 60,
+        };
+        assert_eq!(
+            plan(Model::Aimed, target, Objective::FewestCommands, &submerged).unwrap(),
+            vec![Vector(Direction::Down, 4), Vector(Direction::Forward, 15)]
+        );
+
+        // Optimal magnitudes, found by a breadth-first search over unit commands
+        let magnitude = |course: Vec<Vector>| course.iter().map(|Vector(_, m)| m).sum::<u32>();
+        for (horizontal, depth, optimal) in [
+            (1, 190, 38),
+            (15, 61, 20),
+            (0, 12, 10),
+            (-7, 30, 12),
+            (2, 77, 23),
+        ] {
+            let target = Location { horizontal, depth };
+            let course = plan(Model::Aimed, target, Objective::LeastMagnitude, &submerged).unwrap();
+            assert_eq!(magnitude(course), optimal);
+        }
+
+        for (horizontal, depth) in [
+            (i64::MIN, 0),
+            (i64::MIN, 5),
+            (i64::MAX, 5),
+            (3, i64::MIN),
+            (0, i64::MAX),
+        ] {
+            let target = Location { horizontal, depth };
+
+            for model in [Model::Plain, Model::Aimed] {
+                for objective in [Objective::FewestCommands, Objective::LeastMagnitude] {
+                    if depth == i64::MAX && model == Model::Aimed {
+                        continue;
+                    }
+
+                    assert_eq!(
+                        plan(model, target, objective, &Constraints::default()),
+                        None
+                    );
+                }
+            }
+        }
+
+        // 2^63 - 1 splits into two factors that each fit in a command
+        let target = Location {
+            horizontal: 0,
+            depth: i64::MAX,
+        };
+        let course = plan(Model::Aimed, target, Objective::FewestCommands, &submerged).unwrap();
+        assert_eq!(course.len(), 3);
+        assert!(verify(Model::Aimed, &course, target, &submerged));
+
+        for (horizontal, depth) in [(1, 100_000_000), (2000, 1_000_000_000), (0, 999_999_937)] {
+            let target = Location { horizontal, depth };
+
+            for objective in [Objective::FewestCommands, Objective::LeastMagnitude] {
+                let course = plan(Model::Aimed, target, objective, &submerged).unwrap();
+                assert!(verify(Model::Aimed, &course, target, &submerged));
+            }
+        }
+    }
+
+    #[test]
+    fn optimiser() {
+        let course = generator("down 3\nup 1\ndown 2\nforward 5\nback 5\nup 4\nforward 2").unwrap();
+
+        assert_eq!(
+            optimise(Model::Aimed, &course),
+            vec![Vector(Direction::Forward, 2)]
+        );
+        assert_eq!(
+            optimise(Model::Plain, &course),
+            vec![Vector(Direction::Forward, 2)]
+        );
+        assert_eq!(optimise(Model::Aimed, &generator(SAMPLE).unwrap()).len(), 5);
+    }
+
+    fn course() -> impl Strategy<Value = Vec<Vector>> {
+        let direction = prop_oneof![
+            Just(Direction::Forward),
+            Just(Direction::Back),
+            Just(Direction::Down),
+            Just(Direction::Up),
+        ];
+
+        prop::collection::vec(
+            (direction, 0..10u32).prop_map(|(direction, magnitude)| Vector(direction, magnitude)),
+            0..50,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn optimise_preserves_final_state(course in course()) {
+            let plain = optimise(Model::Plain, &course);
+            prop_assert!(plain.len() <= course.len().min(2));
+            prop_assert_eq!(
+                navigate(Location::default(), &plain),
+                navigate(Location::default(), &course)
+            );
+
+            let aimed = optimise(Model::Aimed, &course);
+            prop_assert!(aimed.len() <= course.len());
+            prop_assert_eq!(
+                navigate(OrientedLocation::default(), &aimed),
+                navigate(OrientedLocation::default(), &course)
+            );
+        }
+    }
 }
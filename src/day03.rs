@@ -1,115 +1,383 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
 use anyhow::Result;
 use aoc_runner_derive::aoc;
+use num::BigUint;
+use thiserror::Error;
 
-fn reading_column(readings: &[String], index: usize) -> Vec<char> {
-    readings
-        .iter()
-        .map(|reading| reading.chars().nth(index).unwrap())
-        .collect()
+pub type Reading = u128;
+
+/// A diagnostic report, with each reading packed into an integer.
+/// Bit indices count from the left, most significant, end of a reading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticReport {
+    width: usize,
+    readings: Vec<Reading>,
 }
 
-fn bit_counts(readings: &[String], index: usize) -> (usize, usize) {
-    let (zeroes, ones): (Vec<char>, Vec<char>) = reading_column(readings, index)
-        .iter()
-        .partition(|bit| **bit == '0');
-    (zeroes.len(), ones.len())
+#[derive(Error, Debug, PartialEq)]
+pub enum ReportError {
+    #[error("The diagnostic report has no readings")]
+    Empty,
+    #[error(
+        "Readings of {width} bits are wider than the supported {} bits",
+        Reading::BITS
+    )]
+    TooWide { width: usize },
+    #[error("Reading {line} has {found} bits, but the first reading has {expected}")]
+    RaggedWidth {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Reading {line} has '{found}' at bit {index}, instead of '0' or '1'")]
+    InvalidCharacter {
+        line: usize,
+        index: usize,
+        found: char,
+    },
+    #[error("No readings remain after filtering on bit {index}")]
+    Exhausted { index: usize },
 }
 
-fn most_common_bit(readings: &[String], index: usize) -> char {
-    let (zeroes, ones) = bit_counts(readings, index);
-    if zeroes > ones {
-        '0'
-    } else {
-        '1'
+impl FromStr for DiagnosticReport {
+    type Err = ReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Lines are numbered from 1, as in an editor, and blank lines are skipped
+        let lines = || {
+            s.lines()
+                .enumerate()
+                .map(|(line, reading)| (line + 1, reading))
+                .filter(|(_, reading)| !reading.is_empty())
+        };
+        let width = lines().next().ok_or(ReportError::Empty)?.1.len();
+
+        if width > Reading::BITS as usize {
+            return Err(ReportError::TooWide { width });
+        }
+
+        let readings = lines()
+            .map(|(line, reading)| {
+                if reading.len() != width {
+                    return Err(ReportError::RaggedWidth {
+                        line,
+                        expected: width,
+                        found: reading.len(),
+                    });
+                }
+
+                reading
+                    .chars()
+                    .enumerate()
+                    .try_fold(0, |packed, (index, bit)| match bit {
+                        '0' => Ok(packed << 1),
+                        '1' => Ok(packed << 1 | 1),
+                        found => Err(ReportError::InvalidCharacter { line, index, found }),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(DiagnosticReport { width, readings })
     }
 }
 
-fn least_common_bit(readings: &[String], index: usize) -> char {
-    let (zeroes, ones) = bit_counts(readings, index);
-    if zeroes <= ones {
-        '0'
-    } else {
-        '1'
+fn bit_counts(readings: &[Reading], mask: Reading) -> (usize, usize) {
+    let ones = readings
+        .iter()
+        .filter(|reading| *reading & mask != 0)
+        .count();
+
+    (readings.len() - ones, ones)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Commonality {
+    Most,
+    Least,
+}
+
+/// How to choose the bit kept at each position when computing a rating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitCriteria {
+    pub keep: Commonality,
+    /// The bit kept when a position has as many zeroes as ones.
+    pub tie: bool,
+    /// Whether to keep filtering after a single candidate remains,
+    /// rather than stopping at it.
+    pub exhaustive: bool,
+}
+
+impl BitCriteria {
+    pub const OXYGEN_GENERATOR: BitCriteria = BitCriteria {
+        keep: Commonality::Most,
+        tie: true,
+        exhaustive: false,
+    };
+
+    pub const CO2_SCRUBBER: BitCriteria = BitCriteria {
+        keep: Commonality::Least,
+        tie: false,
+        exhaustive: false,
+    };
+
+    pub fn bit(&self, zeroes: usize, ones: usize) -> bool {
+        match (zeroes.cmp(&ones), self.keep) {
+            (Ordering::Equal, _) => self.tie,
+            (Ordering::Less, Commonality::Most) | (Ordering::Greater, Commonality::Least) => true,
+            (Ordering::Greater, Commonality::Most) | (Ordering::Less, Commonality::Least) => false,
+        }
     }
 }
 
-fn gamma(readings: &[String]) -> Result<u32> {
-    let mut gamma = String::new();
+impl DiagnosticReport {
+    pub fn width(&self) -> usize {
+        self.width
+    }
 
-    for i in 0..readings[0].len() {
-        gamma.push(most_common_bit(readings, i));
+    pub fn readings(&self) -> &[Reading] {
+        &self.readings
     }
 
-    u32::from_str_radix(&gamma, 2).map_err(Into::into)
-}
+    fn mask(&self, index: usize) -> Reading {
+        1 << (self.width - 1 - index)
+    }
+
+    fn masks(&self) -> impl Iterator<Item = Reading> + '_ {
+        (0..self.width).map(|index| self.mask(index))
+    }
 
-fn epsilon(readings: &[String]) -> Result<u32> {
-    let mut epsilon = String::new();
+    /// The number of zeroes and ones at bit `index` of every reading.
+    pub fn bit_counts(&self, index: usize) -> (usize, usize) {
+        bit_counts(&self.readings, self.mask(index))
+    }
+
+    /// The bits chosen by `criteria` for each position, across every reading.
+    pub fn column_bits(&self, criteria: &BitCriteria) -> Reading {
+        self.masks()
+            .filter(|mask| {
+                let (zeroes, ones) = bit_counts(&self.readings, *mask);
+                criteria.bit(zeroes, ones)
+            })
+            .fold(0, |bits, mask| bits | mask)
+    }
+
+    pub fn gamma(&self) -> Reading {
+        self.column_bits(&BitCriteria::OXYGEN_GENERATOR)
+    }
+
+    pub fn epsilon(&self) -> Reading {
+        self.column_bits(&BitCriteria::CO2_SCRUBBER)
+    }
+
+    /// Narrows down the readings, one bit at a time, to those whose bit
+    /// matches the one chosen by `criteria` for the remaining candidates.
+    /// Also returns the number of candidates before the first step and after each one.
+    fn filter(&self, criteria: &BitCriteria) -> (Result<Reading, ReportError>, Vec<usize>) {
+        let mut candidates = self.readings.clone();
+        let mut counts = vec![candidates.len()];
+
+        for (index, mask) in self.masks().enumerate() {
+            if candidates.len() <= 1 && !criteria.exhaustive {
+                break;
+            }
+
+            let (zeroes, ones) = bit_counts(&candidates, mask);
+            let bit = criteria.bit(zeroes, ones);
+            candidates.retain(|candidate| (candidate & mask != 0) == bit);
+            counts.push(candidates.len());
+
+            if candidates.is_empty() {
+                return (Err(ReportError::Exhausted { index }), counts);
+            }
+        }
+
+        (
+            candidates.first().copied().ok_or(ReportError::Empty),
+            counts,
+        )
+    }
+
+    pub fn rating(&self, criteria: &BitCriteria) -> Result<Reading, ReportError> {
+        self.filter(criteria).0
+    }
+
+    /// The number of candidates left as a rating is filtered, starting with every reading.
+    pub fn candidate_counts(&self, criteria: &BitCriteria) -> Vec<usize> {
+        self.filter(criteria).1
+    }
+
+    pub fn column_stats(&self) -> Vec<ColumnStats> {
+        (0..self.width)
+            .map(|index| {
+                let (zeroes, ones) = self.bit_counts(index);
+                ColumnStats {
+                    index,
+                    zeroes,
+                    ones,
+                }
+            })
+            .collect()
+    }
 
-    for i in 0..readings[0].len() {
-        epsilon.push(least_common_bit(readings, i));
+    /// The bit positions with as many zeroes as ones.
+    pub fn tied_columns(&self) -> Vec<usize> {
+        self.column_stats()
+            .iter()
+            .filter(|stats| stats.is_tied())
+            .map(|stats| stats.index)
+            .collect()
     }
 
-    u32::from_str_radix(&epsilon, 2).map_err(Into::into)
+    pub fn oxygen_generator(&self) -> Result<Reading, ReportError> {
+        self.rating(&BitCriteria::OXYGEN_GENERATOR)
+    }
+
+    pub fn co2_scrubber(&self) -> Result<Reading, ReportError> {
+        self.rating(&BitCriteria::CO2_SCRUBBER)
+    }
 }
 
-#[aoc(day3, part1)]
-fn part1(input: &str) -> Result<u64> {
-    let input_str: Vec<String> = input.lines().map(ToOwned::to_owned).collect();
+/// Statistics for one bit position across a diagnostic report.
+/// Columns with no entropy are stuck at a single value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub index: usize,
+    pub zeroes: usize,
+    pub ones: usize,
+}
+
+impl ColumnStats {
+    /// The proportion of readings with a one at this position.
+    pub fn ratio(&self) -> f64 {
+        self.ones as f64 / (self.zeroes + self.ones) as f64
+    }
 
-    let gamma = gamma(&input_str)?;
-    let epsilon = epsilon(&input_str)?;
+    /// The Shannon entropy of the position, in bits.
+    pub fn entropy(&self) -> f64 {
+        [self.ratio(), 1.0 - self.ratio()]
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| -p * p.log2())
+            .sum()
+    }
 
-    Ok(gamma as u64 * epsilon as u64)
+    pub fn is_tied(&self) -> bool {
+        self.zeroes == self.ones
+    }
 }
 
-fn filter_readings(readings: Vec<String>, index: usize, filter_bit: char) -> Vec<String> {
-    readings
-        .into_iter()
-        .filter(|reading| reading.chars().nth(index).unwrap() == filter_bit)
-        .collect()
+impl fmt::Display for ColumnStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "bit {:>3}: {:>6} zeroes {:>6} ones  ratio {:.3}  entropy {:.3}{}",
+            self.index,
+            self.zeroes,
+            self.ones,
+            self.ratio(),
+            self.entropy(),
+            if self.is_tied() { "  tied" } else { "" }
+        )
+    }
 }
 
-fn oxygen_generator(readings: Vec<String>) -> Result<u32> {
-    let mut candidates = readings;
+#[derive(Debug, Default, Clone)]
+struct TrieNode {
+    count: usize,
+    children: [Option<usize>; 2],
+}
+
+/// A binary trie of readings, most significant bit first,
+/// with the number of readings below each node.
+#[derive(Debug, Clone)]
+struct ReadingTrie {
+    width: usize,
+    nodes: Vec<TrieNode>,
+}
 
-    for i in 0.. {
-        let mcb = most_common_bit(&candidates, i);
+impl From<&DiagnosticReport> for ReadingTrie {
+    fn from(report: &DiagnosticReport) -> Self {
+        let mut nodes = vec![TrieNode::default()];
 
-        candidates = filter_readings(candidates, i, mcb);
+        for reading in &report.readings {
+            let mut node = 0;
+            nodes[node].count += 1;
 
-        if candidates.len() == 1 {
-            break;
+            for mask in report.masks() {
+                let bit = (reading & mask != 0) as usize;
+
+                node = match nodes[node].children[bit] {
+                    Some(child) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        nodes[node].children[bit] = Some(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+                nodes[node].count += 1;
+            }
         }
-    }
 
-    u32::from_str_radix(&candidates[0], 2).map_err(Into::into)
+        ReadingTrie {
+            width: report.width,
+            nodes,
+        }
+    }
 }
 
-fn co2_scrubber(readings: Vec<String>) -> Result<u32> {
-    let mut candidates = readings;
+impl ReadingTrie {
+    fn count(&self, child: Option<usize>) -> usize {
+        child.map_or(0, |child| self.nodes[child].count)
+    }
+
+    /// Walks down the trie, choosing the branch picked by `criteria` from the
+    /// size of each subtree, until a single reading remains.
+    fn rating(&self, criteria: &BitCriteria) -> Result<Reading, ReportError> {
+        let mut node = 0;
+        let mut rating = 0;
 
-    for i in 0.. {
-        let lcb = least_common_bit(&candidates, i);
+        for index in 0..self.width {
+            let [zeroes, ones] = self.nodes[node].children;
 
-        candidates = filter_readings(candidates, i, lcb);
+            let bit = if self.nodes[node].count == 1 && !criteria.exhaustive {
+                ones.is_some()
+            } else {
+                criteria.bit(self.count(zeroes), self.count(ones))
+            };
 
-        if candidates.len() == 1 {
-            break;
+            node =
+                self.nodes[node].children[bit as usize].ok_or(ReportError::Exhausted { index })?;
+            rating = rating << 1 | bit as Reading;
         }
+
+        Ok(rating)
     }
+}
+
+#[aoc(day3, part1)]
+fn part1(input: &str) -> Result<BigUint> {
+    let report: DiagnosticReport = input.parse()?;
 
-    u32::from_str_radix(&candidates[0], 2).map_err(Into::into)
+    Ok(BigUint::from(report.gamma()) * report.epsilon())
 }
 
 #[aoc(day3, part2)]
-fn part2(input: &str) -> Result<u64> {
-    let input_chars: Vec<String> = input.lines().map(ToOwned::to_owned).collect();
+fn part2(input: &str) -> Result<BigUint> {
+    let report: DiagnosticReport = input.parse()?;
 
-    let oxygen_generator = oxygen_generator(input_chars.clone())?;
-    let co2_scrubber = co2_scrubber(input_chars)?;
+    Ok(BigUint::from(report.oxygen_generator()?) * report.co2_scrubber()?)
+}
+
+#[aoc(day3, part2, trie)]
+fn part2_trie(input: &str) -> Result<BigUint> {
+    let report: DiagnosticReport = input.parse()?;
+    let trie = ReadingTrie::from(&report);
 
-    Ok(oxygen_generator as u64 * co2_scrubber as u64)
+    Ok(BigUint::from(trie.rating(&BitCriteria::OXYGEN_GENERATOR)?)
+        * trie.rating(&BitCriteria::CO2_SCRUBBER)?)
 }
 
 #[cfg(test)]
@@ -131,11 +399,178 @@ mod tests {
 
     #[test]
     fn sample1() {
-        assert_eq!(part1(SAMPLE).unwrap(), 198);
+        assert_eq!(part1(SAMPLE).unwrap(), BigUint::from(198u32));
     }
 
     #[test]
     fn sample2() {
-        assert_eq!(part2(SAMPLE).unwrap(), 230);
+        assert_eq!(part2(SAMPLE).unwrap(), BigUint::from(230u32));
+    }
+
+    #[test]
+    fn trie2() {
+        assert_eq!(part2_trie(SAMPLE).unwrap(), BigUint::from(230u32));
+    }
+
+    #[test]
+    fn trie_matches_filtering() {
+        // Reports of 2 to 6 readings, taken from a scrambled sequence of 4-bit numbers
+        let readings: Vec<Reading> = (0..64).map(|i| (i * 7 + i / 5) % 16).collect();
+
+        for window in 2..=6 {
+            for readings in readings.windows(window) {
+                let report = DiagnosticReport {
+                    width: 4,
+                    readings: readings.to_vec(),
+                };
+                let trie = ReadingTrie::from(&report);
+
+                assert_eq!(
+                    trie.rating(&BitCriteria::OXYGEN_GENERATOR).ok(),
+                    report.oxygen_generator().ok()
+                );
+                assert_eq!(
+                    trie.rating(&BitCriteria::CO2_SCRUBBER).ok(),
+                    report.co2_scrubber().ok()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn report() {
+        let report: DiagnosticReport = SAMPLE.parse().unwrap();
+
+        assert_eq!(report.width(), 5);
+        assert_eq!(report.bit_counts(0), (5, 7));
+        assert_eq!(report.gamma(), 22);
+        assert_eq!(report.epsilon(), 9);
+        assert_eq!(report.oxygen_generator().unwrap(), 23);
+        assert_eq!(report.co2_scrubber().unwrap(), 10);
+    }
+
+    #[test]
+    fn wide_readings() {
+        // The sample, with each reading repeated to 60 bits
+        let wide: String = SAMPLE.lines().map(|line| line.repeat(12) + "\n").collect();
+        let report: DiagnosticReport = wide.parse().unwrap();
+        let repeated = |reading: Reading| (0..12).fold(0, |wide, _| wide << 5 | reading);
+
+        assert_eq!(report.gamma(), repeated(22));
+        assert_eq!(report.epsilon(), repeated(9));
+        assert_eq!(
+            part1(&wide).unwrap(),
+            BigUint::from(repeated(22)) * repeated(9)
+        );
+        assert_eq!(
+            part2(&wide).unwrap(),
+            BigUint::from(repeated(23)) * repeated(10)
+        );
+
+        assert_eq!(
+            "1".repeat(129).parse::<DiagnosticReport>(),
+            Err(ReportError::TooWide { width: 129 })
+        );
+    }
+
+    #[test]
+    fn custom_criteria() {
+        let report: DiagnosticReport = SAMPLE.parse().unwrap();
+        let nitrogen = BitCriteria {
+            tie: false,
+            ..BitCriteria::OXYGEN_GENERATOR
+        };
+
+        // The first tie, between 10110 and 10111, now keeps 10110
+        assert_eq!(report.rating(&nitrogen).unwrap(), 22);
+        assert_eq!(ReadingTrie::from(&report).rating(&nitrogen).unwrap(), 22);
+
+        let exhaustive = BitCriteria {
+            exhaustive: true,
+            ..BitCriteria::CO2_SCRUBBER
+        };
+        assert_eq!(
+            report.rating(&exhaustive),
+            Err(ReportError::Exhausted { index: 3 })
+        );
+        assert_eq!(
+            ReadingTrie::from(&report).rating(&exhaustive),
+            Err(ReportError::Exhausted { index: 3 })
+        );
+    }
+
+    #[test]
+    fn malformed_reports() {
+        assert_eq!("".parse::<DiagnosticReport>(), Err(ReportError::Empty));
+        assert_eq!("\n\n".parse::<DiagnosticReport>(), Err(ReportError::Empty));
+        assert!(
+            matches!(part1("\n"), Err(error) if error.downcast_ref() == Some(&ReportError::Empty))
+        );
+        assert_eq!(
+            "\n00100\n11110\n"
+                .parse::<DiagnosticReport>()
+                .map(|report| report.readings),
+            Ok(vec![0b00100, 0b11110])
+        );
+        assert_eq!(
+            "\n00100\n1111".parse::<DiagnosticReport>(),
+            Err(ReportError::RaggedWidth {
+                line: 3,
+                expected: 5,
+                found: 4
+            })
+        );
+        assert_eq!(
+            "00100\n1111\n10110".parse::<DiagnosticReport>(),
+            Err(ReportError::RaggedWidth {
+                line: 2,
+                expected: 5,
+                found: 4
+            })
+        );
+        assert_eq!(
+            "00100\n11120".parse::<DiagnosticReport>(),
+            Err(ReportError::InvalidCharacter {
+                line: 2,
+                index: 3,
+                found: '2'
+            })
+        );
+
+        let report: DiagnosticReport = "101\n101".parse().unwrap();
+        assert_eq!(
+            report.co2_scrubber(),
+            Err(ReportError::Exhausted { index: 0 })
+        );
+        assert!(part2("101\n101").is_err());
+    }
+
+    #[test]
+    fn statistics() {
+        let report: DiagnosticReport = SAMPLE.parse().unwrap();
+        let stats = report.column_stats();
+
+        assert_eq!(stats.len(), 5);
+        assert_eq!((stats[0].zeroes, stats[0].ones), (5, 7));
+        assert!((stats[0].ratio() - 7.0 / 12.0).abs() < 1e-9);
+        assert!((stats[2].entropy() - 0.918_295_834).abs() < 1e-9);
+        assert_eq!(
+            stats[4].to_string(),
+            "bit   4:      7 zeroes      5 ones  ratio 0.417  entropy 0.980"
+        );
+        assert_eq!(report.tied_columns(), vec![]);
+
+        let tied: DiagnosticReport = "10\n01\n11\n00".parse().unwrap();
+        assert_eq!(tied.tied_columns(), vec![0, 1]);
+        assert_eq!(tied.column_stats()[0].entropy(), 1.0);
+
+        assert_eq!(
+            report.candidate_counts(&BitCriteria::OXYGEN_GENERATOR),
+            vec![12, 7, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            report.candidate_counts(&BitCriteria::CO2_SCRUBBER),
+            vec![12, 5, 2, 1]
+        );
     }
 }
@@ -1,148 +1,1086 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::time::Duration;
+use std::{iter, slice, thread};
+
 use anyhow::Result;
 use aoc_runner_derive::{aoc, aoc_generator};
+use colored::Colorize;
+use itertools::{izip, Itertools};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use thiserror::Error;
 
-#[derive(Debug, Clone)]
-struct Board(Vec<Vec<u32>>);
+/// A bingo card: a non-empty rectangle of distinct numbers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Board(Vec<Vec<u32>>);
 
-#[derive(Debug, Clone)]
-struct MarkedBoard(Vec<Vec<(u32, bool)>>);
+/// Rows are numbered from 1, as in the puzzle input.
+#[derive(Error, Debug, PartialEq)]
+pub enum BoardError {
+    #[error("The board has no numbers")]
+    Empty,
+    #[error("Row {row} has {found} numbers, but the first row has {expected}")]
+    Ragged {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("{number} appears more than once")]
+    DuplicateNumber { number: u32 },
+}
 
-impl MarkedBoard {
-    fn mark(self, called: u32) -> Self {
-        Self(
-            self.0
-                .into_iter()
-                .map(|line| {
-                    line.into_iter()
-                        .map(|(number, mark)| {
-                            if number == called {
-                                (number, true)
-                            } else {
-                                (number, mark)
-                            }
-                        })
-                        .collect()
-                })
-                .collect(),
-        )
+/// Draws and boards are numbered from 1, as in the puzzle input.
+#[derive(Error, Debug, PartialEq)]
+pub enum BingoError {
+    #[error("Unable to parse number: {0}")]
+    InvalidNumber(#[from] ParseIntError),
+    #[error("{number} is drawn at both {first} and {second}")]
+    RepeatedDraw {
+        number: u32,
+        first: usize,
+        second: usize,
+    },
+    #[error("Board {index} is invalid: {source}")]
+    InvalidBoard {
+        index: usize,
+        #[source]
+        source: BoardError,
+    },
+    #[error(transparent)]
+    InvalidRules(#[from] RuleError),
+    #[error("No board wins")]
+    NoWinner,
+    #[error("Board {board} never wins, so there is no last winner")]
+    NeverWins { board: usize },
+}
+
+impl Board {
+    pub fn new(rows: Vec<Vec<u32>>) -> Result<Self, BoardError> {
+        let columns = rows.first().map_or(0, Vec::len);
+
+        if columns == 0 {
+            return Err(BoardError::Empty);
+        }
+
+        if let Some((row, line)) = rows
+            .iter()
+            .enumerate()
+            .find(|(_, line)| line.len() != columns)
+        {
+            return Err(BoardError::Ragged {
+                row: row + 1,
+                expected: columns,
+                found: line.len(),
+            });
+        }
+
+        if let Some(number) = rows.iter().flatten().duplicates().next() {
+            return Err(BoardError::DuplicateNumber { number: *number });
+        }
+
+        Ok(Board(rows))
+    }
+
+    pub fn rows(&self) -> &[Vec<u32>] {
+        &self.0
     }
 
-    fn is_winner(&self) -> bool {
-        let rows = self.0.iter().any(|line| line.iter().all(|(_, mark)| *mark));
-        let columns = (0..self.0.len()).any(|i| self.0.iter().all(|line| line[i].1));
+    pub fn height(&self) -> usize {
+        self.0.len()
+    }
 
-        rows || columns
+    pub fn width(&self) -> usize {
+        self.0[0].len()
     }
+}
 
-    fn score(&self, called: u32) -> u32 {
-        let unmarked: u32 = self
-            .0
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    board: usize,
+    row: usize,
+    column: usize,
+}
+
+/// A pattern of cells that wins a board once they are all marked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WinRule {
+    Rows,
+    Columns,
+    /// Either diagonal of a square board.
+    Diagonals,
+    Corners,
+    Blackout,
+    /// The cells set in the mask, which is laid over the top-left of the board.
+    Mask(Vec<Vec<bool>>),
+}
+
+/// Games are rejected when a rule can't be won on some board, rather than
+/// leaving that board with fewer ways to win, or none.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RuleError {
+    #[error("The game has no win rules")]
+    NoRules,
+    #[error("{rule:?} can't be won on board {board}, which is {rows}x{columns}")]
+    Inapplicable {
+        rule: WinRule,
+        board: usize,
+        rows: usize,
+        columns: usize,
+    },
+}
+
+/// Checks that every rule can be won on every board. Boards are numbered from 1.
+fn check_rules(boards: &[Board], rules: &[WinRule]) -> Result<(), RuleError> {
+    if rules.is_empty() {
+        return Err(RuleError::NoRules);
+    }
+
+    for (index, board) in boards.iter().enumerate() {
+        let (rows, columns) = (board.height(), board.width());
+
+        if let Some(rule) = rules
             .iter()
-            .map(|line| {
-                line.iter()
-                    .filter(|(_, mark)| !mark)
-                    .map(|(number, _)| number)
-                    .sum::<u32>()
-            })
-            .sum();
+            .find(|rule| rule.lines(rows, columns).is_none())
+        {
+            return Err(RuleError::Inapplicable {
+                rule: rule.clone(),
+                board: index + 1,
+                rows,
+                columns,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+pub const CLASSIC: &[WinRule] = &[WinRule::Rows, WinRule::Columns];
 
-        unmarked * called
+impl WinRule {
+    /// The sets of cells, any of which wins a board of the given size, or `None`
+    /// if the rule can't be won on a board of that size: `Diagonals` on a board
+    /// that isn't square, or a `Mask` that sets no cells or doesn't fit.
+    fn lines(&self, rows: usize, columns: usize) -> Option<Vec<Vec<(usize, usize)>>> {
+        let lines = match self {
+            WinRule::Rows => (0..rows)
+                .map(|row| (0..columns).map(|column| (row, column)).collect())
+                .collect(),
+            WinRule::Columns => (0..columns)
+                .map(|column| (0..rows).map(|row| (row, column)).collect())
+                .collect(),
+            WinRule::Diagonals if rows == columns => vec![
+                (0..rows).map(|i| (i, i)).collect(),
+                (0..rows).map(|i| (i, columns - 1 - i)).collect(),
+            ],
+            WinRule::Diagonals => return None,
+            WinRule::Corners => vec![[
+                (0, 0),
+                (0, columns - 1),
+                (rows - 1, 0),
+                (rows - 1, columns - 1),
+            ]
+            .into_iter()
+            .sorted()
+            .dedup()
+            .collect()],
+            WinRule::Blackout => vec![(0..rows).cartesian_product(0..columns).collect()],
+            WinRule::Mask(mask) => {
+                let cells: Vec<_> = mask
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(row, line)| {
+                        line.iter()
+                            .enumerate()
+                            .filter(|(_, set)| **set)
+                            .map(move |(column, _)| (row, column))
+                    })
+                    .collect();
+
+                if cells.is_empty()
+                    || cells
+                        .iter()
+                        .any(|(row, column)| *row >= rows || *column >= columns)
+                {
+                    return None;
+                }
+
+                vec![cells]
+            }
+        };
+
+        Some(lines)
     }
 }
 
-impl From<Board> for MarkedBoard {
-    fn from(board: Board) -> Self {
-        MarkedBoard(
-            board
-                .0
-                .into_iter()
-                .map(|line| line.into_iter().map(|num| (num, false)).collect())
+#[derive(Debug, Clone)]
+struct Line {
+    rule: usize,
+    cells: usize,
+    hits: usize,
+}
+
+#[derive(Debug, Clone)]
+struct BoardState {
+    columns: usize,
+    marked: Vec<bool>,
+    cell_lines: Vec<Vec<usize>>,
+    lines: Vec<Line>,
+    unmarked: u64,
+    won: bool,
+}
+
+impl BoardState {
+    fn new(board: &Board, rules: &[WinRule]) -> Self {
+        let (rows, columns) = (board.height(), board.width());
+
+        let mut cell_lines = vec![Vec::new(); rows * columns];
+        let mut lines = Vec::new();
+
+        for (rule_index, rule) in rules.iter().enumerate() {
+            for cells in rule.lines(rows, columns).into_iter().flatten() {
+                for (row, column) in &cells {
+                    cell_lines[row * columns + column].push(lines.len());
+                }
+
+                lines.push(Line {
+                    rule: rule_index,
+                    cells: cells.len(),
+                    hits: 0,
+                });
+            }
+        }
+
+        BoardState {
+            columns,
+            marked: vec![false; rows * columns],
+            cell_lines,
+            lines,
+            unmarked: board.0.iter().flatten().map(|number| *number as u64).sum(),
+            won: false,
+        }
+    }
+
+    /// Marks a single cell, returning the first rule (in the order given to the game)
+    /// with a line it completed.
+    fn mark(&mut self, row: usize, column: usize, called: u32) -> Option<usize> {
+        let cell = row * self.columns + column;
+
+        if self.marked[cell] {
+            return None;
+        }
+
+        self.marked[cell] = true;
+        self.unmarked -= called as u64;
+
+        let mut completed = None;
+
+        for line in &self.cell_lines[cell] {
+            let line = &mut self.lines[*line];
+            line.hits += 1;
+
+            if line.hits == line.cells {
+                completed = Some(completed.map_or(line.rule, |rule: usize| rule.min(line.rule)));
+            }
+        }
+
+        completed
+    }
+}
+
+/// A game of bingo, with every number indexed to the cells it appears in,
+/// so that each call only touches the cells it marks.
+#[derive(Debug, Clone)]
+struct Bingo {
+    boards: Vec<BoardState>,
+    index: HashMap<u32, Vec<Cell>>,
+}
+
+impl Bingo {
+    fn new(boards: &[Board], rules: &[WinRule]) -> Self {
+        let mut index: HashMap<u32, Vec<Cell>> = HashMap::new();
+
+        for (board_index, board) in boards.iter().enumerate() {
+            for (row, line) in board.0.iter().enumerate() {
+                for (column, number) in line.iter().enumerate() {
+                    index.entry(*number).or_default().push(Cell {
+                        board: board_index,
+                        row,
+                        column,
+                    });
+                }
+            }
+        }
+
+        Bingo {
+            boards: boards
+                .iter()
+                .map(|board| BoardState::new(board, rules))
                 .collect(),
-        )
+            index,
+        }
+    }
+
+    /// Marks `called` on every board, returning the boards that won because of it,
+    /// along with the index of the rule each one won by.
+    fn call(&mut self, called: u32) -> Vec<(usize, usize)> {
+        let mut winners = Vec::new();
+
+        for cell in self.index.get(&called).into_iter().flatten() {
+            let board = &mut self.boards[cell.board];
+
+            if let Some(rule) = board.mark(cell.row, cell.column, called) {
+                if !board.won {
+                    board.won = true;
+                    winners.push((cell.board, rule));
+                }
+            }
+        }
+
+        winners
+    }
+
+    fn score(&self, board: usize, called: u32) -> u64 {
+        self.boards[board].unmarked * called as u64
+    }
+
+    /// The boards that `number` appears on, in order.
+    fn boards_with(&self, number: u32) -> Vec<usize> {
+        self.index
+            .get(&number)
+            .into_iter()
+            .flatten()
+            .map(|cell| cell.board)
+            .dedup()
+            .collect()
+    }
+
+    fn render(&self, index: usize, board: &Board, width: usize) -> String {
+        let state = &self.boards[index];
+        let mut rendered = String::new();
+
+        for (row, line) in board.0.iter().enumerate() {
+            let mut cells = Vec::new();
+
+            for (column, number) in line.iter().enumerate() {
+                let cell = row * state.columns + column;
+                let text = format!("{:>width$}", number, width = width);
+
+                let winning = state.cell_lines[cell]
+                    .iter()
+                    .any(|line| state.lines[*line].hits == state.lines[*line].cells);
+
+                let text = if winning {
+                    text.yellow().bold()
+                } else if state.marked[cell] {
+                    text.green().bold()
+                } else {
+                    text.dimmed()
+                };
+
+                cells.push(text.to_string());
+            }
+
+            rendered.push_str(&cells.join(" "));
+            rendered.push('\n');
+        }
+
+        rendered
     }
 }
 
 #[aoc_generator(day4)]
-fn generator(input: &str) -> (Vec<u32>, Vec<Board>) {
+pub fn generator(input: &str) -> Result<(Vec<u32>, Vec<Board>), BingoError> {
     let mut blocks = input.split("\n\n");
 
-    let numbers = blocks
+    let numbers: Vec<u32> = blocks
         .next()
-        .unwrap()
+        .unwrap_or_default()
+        .trim()
         .split(',')
         .map(str::parse)
-        .collect::<Result<_, _>>()
-        .unwrap();
+        .collect::<Result<_, _>>()?;
+
+    let mut drawn = HashMap::new();
+    for (second, number) in numbers.iter().enumerate() {
+        if let Some(first) = drawn.insert(number, second) {
+            return Err(BingoError::RepeatedDraw {
+                number: *number,
+                first: first + 1,
+                second: second + 1,
+            });
+        }
+    }
 
     let boards = blocks
-        .map(|block| {
-            Board(
-                block
-                    .lines()
-                    .map(|line| {
-                        line.split_whitespace()
-                            .map(str::parse)
-                            .collect::<Result<_, _>>()
-                            .unwrap()
-                    })
-                    .collect(),
-            )
+        .enumerate()
+        .map(|(index, block)| {
+            let rows = block
+                .lines()
+                .map(|line| line.split_whitespace().map(str::parse).collect())
+                .collect::<Result<_, _>>()?;
+
+            Board::new(rows).map_err(|source| BingoError::InvalidBoard {
+                index: index + 1,
+                source,
+            })
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
+
+    Ok((numbers, boards))
+}
 
-    (numbers, boards)
+/// A board winning, with the rule it won by and its score at the time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Win {
+    pub board_index: usize,
+    pub call_index: usize,
+    pub called: u32,
+    pub score: u64,
+    pub rule: WinRule,
+}
+
+/// Every win of a game, in the order the boards win.
+/// Boards that win on the same call are ordered by their index.
+pub struct Wins<'a> {
+    bingo: Bingo,
+    rules: &'a [WinRule],
+    calls: iter::Enumerate<slice::Iter<'a, u32>>,
+    pending: VecDeque<Win>,
+    remaining: usize,
+}
+
+impl<'a> Wins<'a> {
+    pub fn new(
+        numbers: &'a [u32],
+        boards: &[Board],
+        rules: &'a [WinRule],
+    ) -> Result<Self, RuleError> {
+        check_rules(boards, rules)?;
+
+        Ok(Wins {
+            bingo: Bingo::new(boards, rules),
+            rules,
+            calls: numbers.iter().enumerate(),
+            pending: VecDeque::new(),
+            remaining: boards.len(),
+        })
+    }
+}
+
+impl<'a> Iterator for Wins<'a> {
+    type Item = Win;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && self.remaining > 0 {
+            let (call_index, called) = self.calls.next()?;
+            let mut winners = self.bingo.call(*called);
+            winners.sort_unstable();
+
+            self.remaining -= winners.len();
+            self.pending
+                .extend(winners.into_iter().map(|(board_index, rule)| Win {
+                    board_index,
+                    call_index,
+                    called: *called,
+                    score: self.bingo.score(board_index, *called),
+                    rule: self.rules[rule].clone(),
+                }));
+        }
+
+        self.pending.pop_front()
+    }
 }
 
 #[aoc(day4, part1)]
-fn part1(input: &(Vec<u32>, Vec<Board>)) -> u32 {
-    let (numbers, boards) = input;
-    let mut marked_boards: Vec<MarkedBoard> = boards
-        .iter()
-        .map(ToOwned::to_owned)
-        .map(Into::into)
-        .collect();
+fn part1((numbers, boards): &(Vec<u32>, Vec<Board>)) -> Result<u64, BingoError> {
+    Wins::new(numbers, boards, CLASSIC)?
+        .next()
+        .map(|win| win.score)
+        .ok_or(BingoError::NoWinner)
+}
+
+// When several boards are last to win on the same call, the lowest indexed
+// of them is chosen.
+#[aoc(day4, part2)]
+fn part2((numbers, boards): &(Vec<u32>, Vec<Board>)) -> Result<u64, BingoError> {
+    let wins: Vec<Win> = Wins::new(numbers, boards, CLASSIC)?.collect();
+    let last = wins.last().ok_or(BingoError::NoWinner)?.call_index;
+
+    if wins.len() != boards.len() {
+        let won: HashSet<usize> = wins.iter().map(|win| win.board_index).collect();
+        let board = (0..boards.len())
+            .find(|board| !won.contains(board))
+            .unwrap();
+        return Err(BingoError::NeverWins { board: board + 1 });
+    }
+
+    wins.into_iter()
+        .find(|win| win.call_index == last)
+        .map(|win| win.score)
+        .ok_or(BingoError::NoWinner)
+}
 
-    for called in numbers {
-        marked_boards = marked_boards
+/// How a board fared over many games with shuffled draws.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardOdds {
+    pub first: f64,
+    pub last: f64,
+    /// The mean number of calls made when the board won, over the games it won.
+    pub expected_calls: Option<f64>,
+}
+
+/// The outcomes of some games, per board.
+///
+/// Shared wins are counted by how many boards shared them rather than as
+/// fractions, so the totals don't depend on the order games are added in.
+#[derive(Debug, Clone)]
+struct OddsTally {
+    first: Vec<HashMap<usize, u64>>,
+    last: Vec<HashMap<usize, u64>>,
+    calls: Vec<(u64, u64)>,
+}
+
+impl OddsTally {
+    fn new(boards: usize) -> Self {
+        OddsTally {
+            first: vec![HashMap::new(); boards],
+            last: vec![HashMap::new(); boards],
+            calls: vec![(0, 0); boards],
+        }
+    }
+
+    fn play(mut self, numbers: &[u32], boards: &[Board], rules: &[WinRule]) -> Self {
+        let mut bingo = Bingo::new(boards, rules);
+        let mut last = Vec::new();
+
+        for (call_index, called) in numbers.iter().enumerate() {
+            let mut winners: Vec<usize> = bingo
+                .call(*called)
+                .into_iter()
+                .map(|(board, _)| board)
+                .collect();
+
+            if winners.is_empty() {
+                continue;
+            }
+
+            winners.sort_unstable();
+            if last.is_empty() {
+                for winner in &winners {
+                    *self.first[*winner].entry(winners.len()).or_default() += 1;
+                }
+            }
+
+            for winner in &winners {
+                let (total, count) = &mut self.calls[*winner];
+                *total += call_index as u64 + 1;
+                *count += 1;
+            }
+
+            last = winners;
+        }
+
+        for winner in &last {
+            *self.last[*winner].entry(last.len()).or_default() += 1;
+        }
+
+        self
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        for (mine, theirs) in self
+            .first
+            .iter_mut()
+            .zip(other.first)
+            .chain(self.last.iter_mut().zip(other.last))
+        {
+            for (shared, games) in theirs {
+                *mine.entry(shared).or_default() += games;
+            }
+        }
+
+        for ((total, count), (other_total, other_count)) in self.calls.iter_mut().zip(other.calls) {
+            *total += other_total;
+            *count += other_count;
+        }
+
+        self
+    }
+}
+
+/// Plays `trials` games, each with its own shuffle of `numbers`, and estimates
+/// each board's chances of being the first and last to win. With no trials,
+/// every chance is zero.
+///
+/// Boards sharing the first or last winning call share that game's credit.
+/// Every game is shuffled by its own stream of a generator seeded with `seed`,
+/// so the results are the same however the games are spread across threads.
+pub fn estimate_odds(
+    numbers: &[u32],
+    boards: &[Board],
+    rules: &[WinRule],
+    trials: usize,
+    seed: u64,
+) -> Result<Vec<BoardOdds>, RuleError> {
+    check_rules(boards, rules)?;
+
+    let tally = (0..trials)
+        .into_par_iter()
+        .fold(
+            || OddsTally::new(boards.len()),
+            |tally, trial| {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                rng.set_stream(trial as u64);
+
+                let mut numbers = numbers.to_vec();
+                numbers.shuffle(&mut rng);
+
+                tally.play(&numbers, boards, rules)
+            },
+        )
+        .reduce(|| OddsTally::new(boards.len()), OddsTally::merge);
+
+    let chance = |games: HashMap<usize, u64>| {
+        if trials == 0 {
+            return 0.0;
+        }
+
+        games
             .into_iter()
-            .map(|board| board.mark(*called))
+            .sorted()
+            .map(|(shared, games)| games as f64 / shared as f64)
+            .sum::<f64>()
+            / trials as f64
+    };
+
+    Ok(izip!(tally.first, tally.last, tally.calls)
+        .map(|(first, last, (total, count))| BoardOdds {
+            first: chance(first),
+            last: chance(last),
+            expected_calls: (count > 0).then(|| total as f64 / count as f64),
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Finish {
+    First,
+    Last,
+}
+
+/// Why one of the target's lines can't be completed before every other board.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Blocker {
+    /// The line needs a number that is never drawn.
+    Undrawable { line: Vec<u32>, number: u32 },
+    /// Drawing the line also completes a line of another board.
+    Dominated {
+        line: Vec<u32>,
+        board: usize,
+        by: Vec<u32>,
+    },
+}
+
+/// A reason the target board can't be made to finish where it was asked to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Impossible {
+    /// Every line of the target is blocked.
+    Blocked(Vec<Blocker>),
+    /// Another board can never win, so the target can't win after it.
+    Unwinnable { board: usize },
+    /// Every line of another board contains a line of the target,
+    /// so the target always wins no later than it.
+    Covered { board: usize },
+    /// No other reason applies, but an exhaustive search found no draw order.
+    Exhausted,
+    /// The rules can't be played on every board.
+    Rules(RuleError),
+}
+
+/// The numbers of every line of `board` under `rules`.
+fn board_lines(board: &Board, rules: &[WinRule]) -> Vec<HashSet<u32>> {
+    rules
+        .iter()
+        .flat_map(|rule| rule.lines(board.height(), board.width()))
+        .flatten()
+        .map(|line| {
+            line.iter()
+                .map(|(row, column)| board.0[*row][*column])
+                .collect()
+        })
+        .collect()
+}
+
+fn sorted(line: &HashSet<u32>) -> Vec<u32> {
+    line.iter().copied().sorted().collect()
+}
+
+struct Rigging {
+    target: usize,
+    lines: Vec<Vec<HashSet<u32>>>,
+    best: Option<Vec<u32>>,
+    visited: HashSet<Vec<u32>>,
+}
+
+impl Rigging {
+    fn won(&self, board: usize, drawn: &HashSet<u32>) -> bool {
+        self.lines[board].iter().any(|line| line.is_subset(drawn))
+    }
+
+    /// Completes a line of a board that hasn't yet won, for every choice of line,
+    /// until every board but the target has won.
+    fn search(&mut self, order: &mut Vec<u32>, drawn: &mut HashSet<u32>) {
+        if self.won(self.target, drawn) || !self.visited.insert(sorted(drawn)) {
+            return;
+        }
+
+        let cost = |line: &HashSet<u32>| line.difference(drawn).count();
+        let unwon: Vec<(usize, usize)> = (0..self.lines.len())
+            .filter(|board| *board != self.target && !self.won(*board, drawn))
+            .map(|board| (board, self.lines[board].iter().map(cost).min().unwrap()))
             .collect();
 
-        if let Some(board) = marked_boards.iter().find(|board| board.is_winner()) {
-            return board.score(*called);
+        let (board, bound) = match unwon.iter().max_by_key(|(_, cost)| *cost) {
+            Some(board) => *board,
+            None => {
+                // Finish with whichever line of the target is closest to complete
+                let finish = self.lines[self.target]
+                    .iter()
+                    .min_by_key(|line| cost(line))
+                    .unwrap();
+                let length = order.len() + cost(finish);
+
+                if !matches!(&self.best, Some(best) if best.len() <= length) {
+                    let mut best = order.clone();
+                    best.extend(sorted(&finish.difference(drawn).copied().collect()));
+                    self.best = Some(best);
+                }
+                return;
+            }
+        };
+
+        // The target still needs at least one more number after the others have won
+        if matches!(&self.best, Some(best) if order.len() + bound + 1 >= best.len()) {
+            return;
         }
-    }
 
-    unreachable!()
+        let choices: Vec<Vec<u32>> = self.lines[board]
+            .iter()
+            .map(|line| sorted(&line.difference(drawn).copied().collect()))
+            .sorted_by_key(Vec::len)
+            .collect();
+
+        for numbers in choices {
+            order.extend(&numbers);
+            drawn.extend(&numbers);
+
+            self.search(order, drawn);
+
+            for number in &numbers {
+                drawn.remove(number);
+            }
+            order.truncate(order.len() - numbers.len());
+        }
+    }
 }
 
-#[aoc(day4, part2)]
-fn part2(input: &(Vec<u32>, Vec<Board>)) -> u32 {
-    let (numbers, boards) = input;
-    let mut marked_boards: Vec<MarkedBoard> = boards
+/// Finds a shortest order in which to draw some of `numbers` so that board
+/// `target` is the first, or the last, to win.
+///
+/// The search for a last win is exhaustive, with branch-and-bound pruning,
+/// so it may be slow for many boards with many overlapping numbers.
+pub fn rig(
+    numbers: &[u32],
+    boards: &[Board],
+    rules: &[WinRule],
+    target: usize,
+    finish: Finish,
+) -> Result<Vec<u32>, Impossible> {
+    check_rules(boards, rules).map_err(Impossible::Rules)?;
+
+    let pool: HashSet<u32> = numbers.iter().copied().collect();
+
+    // Lines that can never be drawn can't help any board
+    let mut blockers = Vec::new();
+    let lines: Vec<Vec<HashSet<u32>>> = boards
         .iter()
-        .map(ToOwned::to_owned)
-        .map(Into::into)
+        .enumerate()
+        .map(|(board, rows)| {
+            board_lines(rows, rules)
+                .into_iter()
+                .filter(|line| match line.difference(&pool).min() {
+                    Some(number) => {
+                        if board == target {
+                            blockers.push(Blocker::Undrawable {
+                                line: sorted(line),
+                                number: *number,
+                            });
+                        }
+                        false
+                    }
+                    None => true,
+                })
+                .collect()
+        })
         .collect();
 
-    for called in numbers {
-        let boards_before_call = marked_boards.clone();
+    match finish {
+        Finish::First => {
+            let mut first = None;
 
-        marked_boards = marked_boards
-            .into_iter()
-            .map(|board| board.mark(*called))
-            .collect();
+            for line in lines[target].iter().sorted_by_key(|line| line.len()) {
+                let dominated =
+                    (0..boards.len())
+                        .filter(|board| *board != target)
+                        .find_map(|board| {
+                            lines[board]
+                                .iter()
+                                .find(|other| other.is_subset(line))
+                                .map(|other| (board, other))
+                        });
+
+                match dominated {
+                    Some((board, other)) => blockers.push(Blocker::Dominated {
+                        line: sorted(line),
+                        board,
+                        by: sorted(other),
+                    }),
+                    None => {
+                        first = Some(sorted(line));
+                        break;
+                    }
+                }
+            }
 
-        if marked_boards.iter().all(|board| board.is_winner()) {
-            for i in 0..boards_before_call.len() {
-                if !boards_before_call[i].is_winner() {
-                    return marked_boards[i].score(*called);
+            first.ok_or(Impossible::Blocked(blockers))
+        }
+        Finish::Last => {
+            if lines[target].is_empty() {
+                return Err(Impossible::Blocked(blockers));
+            }
+
+            for board in (0..boards.len()).filter(|board| *board != target) {
+                if lines[board].is_empty() {
+                    return Err(Impossible::Unwinnable { board });
+                }
+
+                if lines[board]
+                    .iter()
+                    .all(|line| lines[target].iter().any(|target| target.is_subset(line)))
+                {
+                    return Err(Impossible::Covered { board });
                 }
             }
+
+            let mut rigging = Rigging {
+                target,
+                lines,
+                best: None,
+                visited: HashSet::new(),
+            };
+            rigging.search(&mut Vec::new(), &mut HashSet::new());
+
+            rigging.best.ok_or(Impossible::Exhausted)
         }
     }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum GameConfigError {
+    #[error("A {rows}x{columns} board needs more than the {available} numbers available")]
+    TooFewNumbers {
+        rows: usize,
+        columns: usize,
+        available: usize,
+    },
+    #[error("The number of {setting} must be at least 1")]
+    Zero { setting: &'static str },
+    #[error("No draw order with a unique first and last winner was found in {attempts} attempts")]
+    NoUniqueWinners { attempts: usize },
+}
 
-    unreachable!()
+/// Settings for generating a game in the puzzle's input format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameConfig {
+    pub boards: usize,
+    pub rows: usize,
+    pub columns: usize,
+    /// The numbers that can appear on boards. Every one of them is drawn.
+    pub numbers: Range<u32>,
+    pub seed: u64,
+    /// Whether to reshuffle the draws until exactly one board wins first,
+    /// and exactly one board wins last after every other board has won.
+    pub unique_winners: bool,
+    pub attempts: usize,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            boards: 100,
+            rows: 5,
+            columns: 5,
+            numbers: 0..100,
+            seed: 0,
+            unique_winners: false,
+            attempts: 1000,
+        }
+    }
+}
+
+fn unique_winners(numbers: &[u32], boards: &[Board]) -> bool {
+    let wins: Vec<Win> = Wins::new(numbers, boards, CLASSIC)
+        .expect("generated boards are never empty")
+        .collect();
+    let distinct = |a: Option<&Win>, b: Option<&Win>| !matches!((a, b), (Some(a), Some(b)) if a.call_index == b.call_index);
+
+    wins.len() == boards.len()
+        && distinct(wins.first(), wins.get(1))
+        && distinct(wins.last(), wins.iter().rev().nth(1))
+}
+
+/// Generates draws and boards, formatted as puzzle input.
+pub fn generate_game(config: &GameConfig) -> Result<String, GameConfigError> {
+    let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+    let pool: Vec<u32> = config.numbers.clone().collect();
+    let cells = config.rows * config.columns;
+
+    for (setting, value) in [
+        ("rows", config.rows),
+        ("columns", config.columns),
+        ("attempts", config.attempts),
+    ] {
+        if value == 0 {
+            return Err(GameConfigError::Zero { setting });
+        }
+    }
+
+    if cells > pool.len() {
+        return Err(GameConfigError::TooFewNumbers {
+            rows: config.rows,
+            columns: config.columns,
+            available: pool.len(),
+        });
+    }
+
+    let boards: Vec<Board> = (0..config.boards)
+        .map(|_| {
+            let numbers: Vec<u32> = pool.choose_multiple(&mut rng, cells).copied().collect();
+            Board(
+                numbers
+                    .chunks(config.columns)
+                    .map(<[u32]>::to_vec)
+                    .collect(),
+            )
+        })
+        .collect();
+
+    let mut draws = pool.clone();
+    draws.shuffle(&mut rng);
+
+    if config.unique_winners {
+        let mut attempts = 1;
+
+        while !unique_winners(&draws, &boards) {
+            if attempts == config.attempts {
+                return Err(GameConfigError::NoUniqueWinners { attempts });
+            }
+
+            draws.shuffle(&mut rng);
+            attempts += 1;
+        }
+    }
+
+    let width = pool.iter().max().map_or(1, |max| max.to_string().len());
+    let mut game = draws.iter().join(",");
+
+    for board in &boards {
+        game.push('\n');
+
+        for row in &board.0 {
+            game.push('\n');
+            game.push_str(
+                &row.iter()
+                    .map(|number| format!("{:>width$}", number, width = width))
+                    .join(" "),
+            );
+        }
+    }
+    game.push('\n');
+
+    Ok(game)
+}
+
+/// Settings for replaying a game in the terminal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Replay {
+    /// How long to pause after each call.
+    pub delay: Option<Duration>,
+    /// Whether to only show the boards marked by each call.
+    pub changed_only: bool,
+}
+
+impl Replay {
+    /// Prints every board after each call, with marked numbers in green
+    /// and the lines that won a board in yellow. Calls and boards are numbered
+    /// from 1.
+    pub fn play<W: Write>(
+        &self,
+        numbers: &[u32],
+        boards: &[Board],
+        rules: &[WinRule],
+        out: &mut W,
+    ) -> io::Result<()> {
+        check_rules(boards, rules)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+        let mut bingo = Bingo::new(boards, rules);
+        let width = boards
+            .iter()
+            .flat_map(|board| board.0.iter().flatten())
+            .max()
+            .map_or(1, |max| max.to_string().len());
+
+        for (call_index, called) in numbers.iter().enumerate() {
+            let winners = bingo.call(*called);
+
+            let shown: Vec<usize> = if self.changed_only {
+                bingo.boards_with(*called)
+            } else {
+                (0..boards.len()).collect()
+            };
+
+            writeln!(
+                out,
+                "{}",
+                format!("Call {}: {}", call_index + 1, called).bold()
+            )?;
+
+            for board in shown {
+                let status = match winners.iter().find(|(winner, _)| *winner == board) {
+                    Some((_, rule)) => format!(" wins by {:?}!", rules[*rule]),
+                    None if bingo.boards[board].won => " (won)".to_owned(),
+                    None => String::new(),
+                };
+
+                writeln!(out, "Board {}{}", board + 1, status)?;
+                write!(out, "{}", bingo.render(board, &boards[board], width))?;
+                writeln!(out)?;
+            }
+
+            if let Some(delay) = self.delay {
+                out.flush()?;
+                thread::sleep(delay);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -171,11 +1109,436 @@ mod tests {
 
     #[test]
     fn sample1() {
-        assert_eq!(part1(&generator(SAMPLE)), 4512);
+        assert_eq!(part1(&generator(SAMPLE).unwrap()), Ok(4512));
     }
 
     #[test]
     fn sample2() {
-        assert_eq!(part2(&generator(SAMPLE)), 1924);
+        assert_eq!(part2(&generator(SAMPLE).unwrap()), Ok(1924));
+    }
+
+    #[test]
+    fn large_scores() {
+        let game = generator("4000000000,3000000000\n\n3000000000 4000000000\n1 2").unwrap();
+
+        assert_eq!(part1(&game), Ok(3 * 3_000_000_000));
+    }
+
+    #[test]
+    fn no_winner() {
+        let game = generator("1,2\n\n1 3\n4 2").unwrap();
+
+        assert_eq!(part1(&game), Err(BingoError::NoWinner));
+        assert_eq!(part2(&game), Err(BingoError::NoWinner));
+
+        let game = generator("1,2,3\n\n1 2\n\n3 9\n\n5 6").unwrap();
+        assert_eq!(part1(&game), Ok(2));
+        assert_eq!(part2(&game), Err(BingoError::NeverWins { board: 3 }));
+    }
+
+    #[test]
+    fn last_winners_tied() {
+        assert_eq!(
+            part2(&generator("1,2,3\n\n1 2\n\n3 9\n\n3 8").unwrap()),
+            Ok(27)
+        );
+    }
+
+    #[test]
+    fn repeated_calls() {
+        let (_, boards) = generator(SAMPLE).unwrap();
+        let mut bingo = Bingo::new(&boards, CLASSIC);
+
+        for called in [22, 22, 13, 17, 11] {
+            assert!(bingo.call(called).is_empty());
+        }
+
+        assert_eq!(bingo.call(0), vec![(0, 0)]);
+        assert_eq!(bingo.score(0, 0), 0);
+        assert_eq!(bingo.score(0, 1), 300 - 63);
+    }
+
+    #[test]
+    fn win_order() {
+        let (numbers, boards) = generator(SAMPLE).unwrap();
+        let wins: Vec<Win> = Wins::new(&numbers, &boards, CLASSIC).unwrap().collect();
+
+        assert_eq!(
+            wins,
+            vec![
+                Win {
+                    board_index: 2,
+                    call_index: 11,
+                    called: 24,
+                    score: 4512,
+                    rule: WinRule::Rows,
+                },
+                Win {
+                    board_index: 0,
+                    call_index: 13,
+                    called: 16,
+                    score: 2192,
+                    rule: WinRule::Rows,
+                },
+                Win {
+                    board_index: 1,
+                    call_index: 14,
+                    called: 13,
+                    score: 1924,
+                    rule: WinRule::Columns,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn win_rules() {
+        let (numbers, boards) = generator(SAMPLE).unwrap();
+        let rules = [
+            WinRule::Diagonals,
+            WinRule::Corners,
+            WinRule::Mask(vec![vec![false, true], vec![true, true]]),
+        ];
+        let wins: Vec<Win> = Wins::new(&numbers, &boards, &rules).unwrap().collect();
+
+        assert_eq!(
+            wins.iter()
+                .map(|win| (win.board_index, win.called, win.rule.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, 2, WinRule::Diagonals),
+                (1, 22, WinRule::Diagonals),
+                (
+                    0,
+                    8,
+                    WinRule::Mask(vec![vec![false, true], vec![true, true]])
+                ),
+            ]
+        );
+
+        let blackout: Vec<Win> = Wins::new(&numbers, &boards, &[WinRule::Blackout])
+            .unwrap()
+            .collect();
+        assert!(blackout.iter().all(|win| win.score == 0));
+    }
+
+    #[test]
+    fn rectangular_boards() {
+        let (numbers, boards) = generator(
+            "1,2,3,4,5,6,7
+
+1 2 3
+4 5 6
+
+7
+5
+3
+
+4 1
+7 2",
+        )
+        .unwrap();
+
+        assert_eq!(
+            boards
+                .iter()
+                .map(|board| (board.height(), board.width()))
+                .collect::<Vec<_>>(),
+            vec![(2, 3), (3, 1), (2, 2)]
+        );
+        assert_eq!(
+            Wins::new(&numbers, &boards, CLASSIC)
+                .unwrap()
+                .map(|win| (win.board_index, win.called, win.rule))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, 2, WinRule::Columns),
+                (0, 3, WinRule::Rows),
+                (1, 3, WinRule::Rows),
+            ]
+        );
+
+        assert_eq!(
+            Wins::new(&numbers, &boards, &[WinRule::Rows, WinRule::Diagonals]).err(),
+            Some(RuleError::Inapplicable {
+                rule: WinRule::Diagonals,
+                board: 1,
+                rows: 2,
+                columns: 3
+            })
+        );
+        let mask = WinRule::Mask(vec![vec![true; 3]]);
+        assert_eq!(
+            estimate_odds(&numbers, &boards[2..], &[mask.clone()], 10, 0),
+            Err(RuleError::Inapplicable {
+                rule: mask,
+                board: 1,
+                rows: 2,
+                columns: 2
+            })
+        );
+        assert_eq!(
+            rig(
+                &numbers,
+                &boards,
+                &[WinRule::Mask(vec![vec![false]])],
+                0,
+                Finish::First
+            ),
+            Err(Impossible::Rules(RuleError::Inapplicable {
+                rule: WinRule::Mask(vec![vec![false]]),
+                board: 1,
+                rows: 2,
+                columns: 3
+            }))
+        );
+        assert!(Replay::default()
+            .play(&numbers, &boards, &[], &mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(
+            generator("1,2,1\n\n1 2"),
+            Err(BingoError::RepeatedDraw {
+                number: 1,
+                first: 1,
+                second: 3
+            })
+        );
+        assert_eq!(
+            generator("1,2\n\n1 2\n\n1 2\n3"),
+            Err(BingoError::InvalidBoard {
+                index: 2,
+                source: BoardError::Ragged {
+                    row: 2,
+                    expected: 2,
+                    found: 1
+                }
+            })
+        );
+        assert_eq!(
+            generator("1,2\n\n1 2\n2 3"),
+            Err(BingoError::InvalidBoard {
+                index: 1,
+                source: BoardError::DuplicateNumber { number: 2 }
+            })
+        );
+        assert!(matches!(
+            generator("1,x\n\n1 2"),
+            Err(BingoError::InvalidNumber(_))
+        ));
+    }
+
+    #[test]
+    fn odds() {
+        let (numbers, boards) = generator(SAMPLE).unwrap();
+        let odds = estimate_odds(&numbers, &boards, CLASSIC, 2000, 2021).unwrap();
+
+        assert_eq!(
+            odds,
+            estimate_odds(&numbers, &boards, CLASSIC, 2000, 2021).unwrap()
+        );
+        assert_ne!(
+            odds,
+            estimate_odds(&numbers, &boards, CLASSIC, 2000, 2022).unwrap()
+        );
+
+        // Every shuffle completes every board, so someone always wins first and last
+        assert!((odds.iter().map(|odds| odds.first).sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!((odds.iter().map(|odds| odds.last).sum::<f64>() - 1.0).abs() < 1e-9);
+        for board in &odds {
+            assert!(board.first > 0.2 && board.first < 0.5);
+            assert!(board.expected_calls.unwrap() > 5.0);
+        }
+
+        assert!(estimate_odds(&numbers, &boards, CLASSIC, 0, 2021)
+            .unwrap()
+            .iter()
+            .all(|odds| *odds
+                == BoardOdds {
+                    first: 0.0,
+                    last: 0.0,
+                    expected_calls: None
+                }));
+    }
+
+    #[test]
+    fn rigged_draws() {
+        let (numbers, boards) = generator(SAMPLE).unwrap();
+
+        for target in 0..boards.len() {
+            let first = rig(&numbers, &boards, CLASSIC, target, Finish::First).unwrap();
+            assert_eq!(first.len(), 5);
+            let win = Wins::new(&first, &boards, CLASSIC).unwrap().next().unwrap();
+            assert_eq!((win.board_index, win.call_index), (target, 4));
+
+            let last = rig(&numbers, &boards, CLASSIC, target, Finish::Last).unwrap();
+            let wins: Vec<Win> = Wins::new(&last, &boards, CLASSIC).unwrap().collect();
+            assert_eq!(wins.len(), 3);
+            assert_eq!(wins[2].board_index, target);
+            assert_eq!(wins[2].call_index, last.len() - 1);
+            assert!(wins[1].call_index < wins[2].call_index);
+        }
+    }
+
+    #[test]
+    fn rigging_shortest_last() {
+        let (numbers, boards) = generator("1,2,3,4,5,6\n\n1 2\n3 4\n\n1 5\n6 2").unwrap();
+
+        // Board 1 wins with 1 and 5, then the target completes its column with 3
+        assert_eq!(
+            rig(&numbers, &boards, CLASSIC, 0, Finish::Last).map(|draws| draws.len()),
+            Ok(3)
+        );
+    }
+
+    #[test]
+    fn rigging_impossible() {
+        let (numbers, boards) =
+            generator("1,2,4,6,7,8,9\n\n1 2\n3 4\n\n2 1\n6 7\n\n4 2\n8 9").unwrap();
+
+        assert_eq!(
+            rig(&numbers, &boards, CLASSIC, 0, Finish::First),
+            Err(Impossible::Blocked(vec![
+                Blocker::Undrawable {
+                    line: vec![3, 4],
+                    number: 3
+                },
+                Blocker::Undrawable {
+                    line: vec![1, 3],
+                    number: 3
+                },
+                Blocker::Dominated {
+                    line: vec![1, 2],
+                    board: 1,
+                    by: vec![1, 2]
+                },
+                Blocker::Dominated {
+                    line: vec![2, 4],
+                    board: 2,
+                    by: vec![2, 4]
+                },
+            ]))
+        );
+
+        let (numbers, boards) = generator("1,2\n\n1 2\n\n2 1").unwrap();
+        assert_eq!(
+            rig(&numbers, &boards, CLASSIC, 1, Finish::Last),
+            Err(Impossible::Covered { board: 0 })
+        );
+
+        let (numbers, boards) = generator("1,2\n\n1 2\n\n3 4").unwrap();
+        assert_eq!(
+            rig(&numbers, &boards, CLASSIC, 0, Finish::Last),
+            Err(Impossible::Unwinnable { board: 1 })
+        );
+    }
+
+    #[test]
+    fn generated_games() {
+        let config = GameConfig {
+            boards: 20,
+            rows: 3,
+            columns: 4,
+            numbers: 5..65,
+            seed: 7,
+            unique_winners: true,
+            ..Default::default()
+        };
+        let game = generate_game(&config).unwrap();
+
+        assert_eq!(game, generate_game(&config).unwrap());
+
+        let (numbers, boards) = generator(&game).unwrap();
+        assert_eq!(
+            numbers.iter().sorted().copied().collect::<Vec<_>>(),
+            (5..65).collect::<Vec<_>>()
+        );
+        assert_eq!(boards.len(), 20);
+        assert!(boards
+            .iter()
+            .all(|board| (board.height(), board.width()) == (3, 4)));
+        assert!(unique_winners(&numbers, &boards));
+
+        assert_eq!(
+            generate_game(&GameConfig {
+                numbers: 0..24,
+                ..Default::default()
+            }),
+            Err(GameConfigError::TooFewNumbers {
+                rows: 5,
+                columns: 5,
+                available: 24
+            })
+        );
+    }
+
+    #[test]
+    fn generated_games_need_cells_and_attempts() {
+        for (config, setting) in [
+            (
+                GameConfig {
+                    rows: 0,
+                    ..Default::default()
+                },
+                "rows",
+            ),
+            (
+                GameConfig {
+                    columns: 0,
+                    ..Default::default()
+                },
+                "columns",
+            ),
+            (
+                GameConfig {
+                    attempts: 0,
+                    unique_winners: true,
+                    ..Default::default()
+                },
+                "attempts",
+            ),
+        ] {
+            assert_eq!(
+                generate_game(&config),
+                Err(GameConfigError::Zero { setting })
+            );
+        }
+    }
+
+    #[test]
+    fn replay() {
+        colored::control::set_override(false);
+        let (numbers, boards) = generator(SAMPLE).unwrap();
+        let mut out = Vec::new();
+
+        Replay {
+            delay: None,
+            changed_only: true,
+        }
+        .play(&numbers, &boards, CLASSIC, &mut out)
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let call = |number: &str| {
+            let start = out.find(&format!("Call {}", number)).unwrap();
+            let end = out[start..].find("\n\nCall").unwrap();
+            &out[start..start + end]
+        };
+
+        // 25 only appears on board 1
+        assert_eq!(
+            call("18: 25"),
+            "Call 18: 25
+Board 2 (won)
+ 3 15  0  2 22
+ 9 18 13 17  5
+19  8  7 25 23
+20 11 10 24  4
+14 21 16 12  6"
+        );
+        assert!(call("12: 24").contains("Board 3 wins by Rows!"));
     }
 }
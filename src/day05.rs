@@ -1,4 +1,6 @@
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
 
 use anyhow::Result;
 use aoc_runner_derive::{aoc, aoc_generator};
@@ -12,10 +14,10 @@ use nom::{
 };
 use num::rational::Ratio;
 
-#[derive(Debug, Clone, Eq, PartialEq, PartialOrd)]
-struct Coordinate {
-    x: u32,
-    y: u32,
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Hash)]
+pub struct Coordinate {
+    pub x: u32,
+    pub y: u32,
 }
 
 impl Ord for Coordinate {
@@ -39,7 +41,7 @@ struct Seafloor {
 }
 
 #[derive(Debug, Clone)]
-struct VentLine(Coordinate, Coordinate);
+pub struct VentLine(Coordinate, Coordinate);
 
 impl VentLine {
     fn is_horizontal(&self) -> bool {
@@ -80,19 +82,24 @@ impl VentLine {
         }
     }
 
-    fn points(&self) -> Vec<Coordinate> {
+    /// The number of steps from one end of the line to the other.
+    fn steps(&self) -> i64 {
         let (step_x, step_y) = self.step();
         let (delta_x, delta_y) = self.delta();
 
-        let steps = if step_x != 0 {
+        if step_x != 0 {
             (delta_x / step_x).abs()
         } else if step_y != 0 {
             (delta_y / step_y).abs()
         } else {
             0
-        };
+        }
+    }
+
+    fn points(&self) -> Vec<Coordinate> {
+        let (step_x, step_y) = self.step();
 
-        (0..=steps)
+        (0..=self.steps())
             .map(|step| Coordinate {
                 x: (self.0.x as i64 + (step * step_x)) as u32,
                 y: (self.0.y as i64 + (step * step_y)) as u32,
@@ -107,7 +114,7 @@ fn vent_line(input: &str) -> IResult<&str, VentLine> {
 }
 
 #[aoc_generator(day5)]
-fn generator(input: &str) -> Vec<VentLine> {
+pub fn generator(input: &str) -> Vec<VentLine> {
     input.lines().map(|l| vent_line(l).unwrap().1).collect()
 }
 
@@ -140,6 +147,473 @@ fn part2(input: &[VentLine]) -> usize {
         .count()
 }
 
+// Above this many cells, a bounding box is counted sparsely instead.
+const DENSE_LIMIT: usize = 1 << 24;
+
+fn overlaps_dense(lines: &[&VentLine], width: usize, height: usize) -> usize {
+    let mut counts = vec![0u32; width * height];
+
+    for point in lines.iter().flat_map(|line| line.points()) {
+        counts[point.y as usize * width + point.x as usize] += 1;
+    }
+
+    counts.into_iter().filter(|count| *count >= 2).count()
+}
+
+fn overlaps_sparse(lines: &[&VentLine]) -> usize {
+    let mut counts: HashMap<Coordinate, u32> = HashMap::new();
+
+    for point in lines.iter().flat_map(|line| line.points()) {
+        *counts.entry(point).or_default() += 1;
+    }
+
+    counts.into_values().filter(|count| *count >= 2).count()
+}
+
+/// Counts the points covered by at least two lines, by counting how often each
+/// point is covered, in an array over the bounding box if it is small enough.
+fn overlaps(lines: &[&VentLine]) -> usize {
+    let (width, height) = lines.iter().flat_map(|line| [&line.0, &line.1]).fold(
+        (0, 0),
+        |(width, height), Coordinate { x, y }| {
+            (width.max(*x as usize + 1), height.max(*y as usize + 1))
+        },
+    );
+
+    match width.checked_mul(height) {
+        Some(cells) if cells <= DENSE_LIMIT => overlaps_dense(lines, width, height),
+        _ => overlaps_sparse(lines),
+    }
+}
+
+#[aoc(day5, part1, counted)]
+fn part1_counted(input: &[VentLine]) -> usize {
+    let vent_lines: Vec<&VentLine> = input
+        .iter()
+        .filter(|line| line.is_horizontal() || line.is_vertical())
+        .collect();
+
+    overlaps(&vent_lines)
+}
+
+#[aoc(day5, part2, counted)]
+fn part2_counted(input: &[VentLine]) -> usize {
+    overlaps(&input.iter().collect::<Vec<_>>())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Orientation {
+    Horizontal,
+    Vertical,
+    Diagonal,
+    AntiDiagonal,
+}
+
+impl Orientation {
+    const ALL: [Orientation; 4] = [
+        Orientation::Horizontal,
+        Orientation::Vertical,
+        Orientation::Diagonal,
+        Orientation::AntiDiagonal,
+    ];
+
+    /// The coefficients `(a, b)` of the lines `a * x + b * y = c` running this way.
+    fn coefficients(&self) -> (i64, i64) {
+        match self {
+            Orientation::Horizontal => (0, 1),
+            Orientation::Vertical => (1, 0),
+            Orientation::Diagonal => (-1, 1),
+            Orientation::AntiDiagonal => (1, 1),
+        }
+    }
+
+    fn intercept(&self, (x, y): (i64, i64)) -> i64 {
+        let (a, b) = self.coefficients();
+        a * x + b * y
+    }
+
+    /// How far along its line a point is; the y coordinate for vertical lines
+    /// and the x coordinate otherwise.
+    fn position(&self, (x, y): (i64, i64)) -> i64 {
+        match self {
+            Orientation::Vertical => y,
+            _ => x,
+        }
+    }
+}
+
+/// A vent line described by the infinite line it lies on and the range of
+/// positions it covers along it.
+#[derive(Debug, Clone)]
+struct Segment {
+    orientation: Orientation,
+    intercept: i64,
+    from: i64,
+    to: i64,
+    xs: (i64, i64),
+    ys: (i64, i64),
+}
+
+impl Segment {
+    /// The segments covering a line. Lines at angles other than multiples of
+    /// 45° are broken up into a segment for each of their points.
+    fn covering(line: &VentLine) -> Vec<Segment> {
+        match Segment::new(line) {
+            Some(segment) => vec![segment],
+            None => line
+                .points()
+                .into_iter()
+                .filter_map(|point| Segment::new(&VentLine(point.clone(), point)))
+                .collect(),
+        }
+    }
+
+    fn new(line: &VentLine) -> Option<Self> {
+        let start = (line.0.x as i64, line.0.y as i64);
+        let end = (line.1.x as i64, line.1.y as i64);
+
+        let orientation = match line.delta() {
+            (_, 0) => Orientation::Horizontal,
+            (0, _) => Orientation::Vertical,
+            (delta_x, delta_y) if delta_x == delta_y => Orientation::Diagonal,
+            (delta_x, delta_y) if delta_x == -delta_y => Orientation::AntiDiagonal,
+            _ => return None,
+        };
+
+        let (from, to) = (orientation.position(start), orientation.position(end));
+
+        Some(Segment {
+            orientation,
+            intercept: orientation.intercept(start),
+            from: from.min(to),
+            to: from.max(to),
+            xs: (start.0.min(end.0), start.0.max(end.0)),
+            ys: (start.1.min(end.1), start.1.max(end.1)),
+        })
+    }
+
+    fn contains(&self, point: (i64, i64)) -> bool {
+        self.orientation.intercept(point) == self.intercept
+            && (self.xs.0..=self.xs.1).contains(&point.0)
+            && (self.ys.0..=self.ys.1).contains(&point.1)
+    }
+
+    /// The lattice point where two segments running different ways cross.
+    fn crossing(&self, other: &Segment) -> Option<(i64, i64)> {
+        let (a1, b1) = self.orientation.coefficients();
+        let (a2, b2) = other.orientation.coefficients();
+        let (c1, c2) = (self.intercept, other.intercept);
+
+        let determinant = a1 * b2 - a2 * b1;
+        let x = c1 * b2 - c2 * b1;
+        let y = a1 * c2 - a2 * c1;
+
+        if x % determinant != 0 || y % determinant != 0 {
+            return None;
+        }
+
+        let point = (x / determinant, y / determinant);
+        (self.contains(point) && other.contains(point)).then_some(point)
+    }
+
+    /// The range of positions shared by two segments on the same line.
+    fn overlap(&self, other: &Segment) -> Option<(i64, i64)> {
+        let (from, to) = (self.from.max(other.from), self.to.min(other.to));
+        (from <= to).then_some((from, to))
+    }
+}
+
+/// Merges ranges of positions so that none of them overlap or touch.
+fn merge(mut ranges: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(i64, i64)> = Vec::with_capacity(ranges.len());
+    for (from, to) in ranges {
+        match merged.last_mut() {
+            Some(last) if from <= last.1 + 1 => last.1 = last.1.max(to),
+            _ => merged.push((from, to)),
+        }
+    }
+
+    merged
+}
+
+/// Counts the points covered by at least two lines without visiting every
+/// point on them.
+///
+/// A sweep along x pairs up each segment with those whose x ranges it
+/// meets. Pairs on the same line contribute the range they share, and pairs
+/// running different ways contribute the single point where they cross. A
+/// crossing that also lies within shared ranges on several lines would be
+/// counted once for each of them, so the extra counts are taken off again.
+fn overlaps_analytic(lines: &[&VentLine]) -> usize {
+    let mut segments: Vec<Segment> = lines
+        .iter()
+        .flat_map(|line| Segment::covering(line))
+        .collect();
+    segments.sort_unstable_by_key(|segment| segment.xs);
+
+    let mut shared: HashMap<(Orientation, i64), Vec<(i64, i64)>> = HashMap::new();
+    let mut crossings: HashSet<(i64, i64)> = HashSet::new();
+    let mut active: Vec<&Segment> = Vec::new();
+
+    for segment in &segments {
+        active.retain(|other| other.xs.1 >= segment.xs.0);
+
+        for other in active
+            .iter()
+            .filter(|other| other.ys.0 <= segment.ys.1 && segment.ys.0 <= other.ys.1)
+        {
+            if other.orientation != segment.orientation {
+                crossings.extend(segment.crossing(other));
+            } else if other.intercept == segment.intercept {
+                if let Some(range) = segment.overlap(other) {
+                    shared
+                        .entry((segment.orientation, segment.intercept))
+                        .or_default()
+                        .push(range);
+                }
+            }
+        }
+
+        active.push(segment);
+    }
+
+    let shared: HashMap<_, _> = shared
+        .into_iter()
+        .map(|(line, ranges)| (line, merge(ranges)))
+        .collect();
+
+    let covered = shared
+        .values()
+        .flatten()
+        .map(|(from, to)| (to - from + 1) as usize)
+        .sum::<usize>();
+
+    crossings.into_iter().fold(covered, |total, point| {
+        let lines = Orientation::ALL
+            .iter()
+            .filter(|orientation| {
+                shared
+                    .get(&(**orientation, orientation.intercept(point)))
+                    .is_some_and(|ranges| {
+                        let position = orientation.position(point);
+                        let index = ranges.partition_point(|(_, to)| *to < position);
+                        ranges.get(index).is_some_and(|(from, _)| *from <= position)
+                    })
+            })
+            .count();
+
+        match lines {
+            0 => total + 1,
+            lines => total - (lines - 1),
+        }
+    })
+}
+
+#[aoc(day5, part1, analytic)]
+fn part1_analytic(input: &[VentLine]) -> usize {
+    let vent_lines: Vec<&VentLine> = input
+        .iter()
+        .filter(|line| line.is_horizontal() || line.is_vertical())
+        .collect();
+
+    overlaps_analytic(&vent_lines)
+}
+
+#[aoc(day5, part2, analytic)]
+fn part2_analytic(input: &[VentLine]) -> usize {
+    overlaps_analytic(&input.iter().collect::<Vec<_>>())
+}
+
+/// How many vent lines cover each point within the bounding box of their ends.
+///
+/// Fields longer than `MAX_SIDE` points on either side are binned into square
+/// blocks of points, each counting how often lines pass through any of them.
+#[derive(Debug, Clone)]
+pub struct VentMap {
+    min: Coordinate,
+    max: Coordinate,
+    scale: u64,
+    width: u32,
+    height: u32,
+    counts: Vec<u64>,
+}
+
+impl VentMap {
+    /// Fields up to this many points across or down are drawn as text.
+    pub const ASCII_LIMIT: u32 = 80;
+
+    /// The most bins a map has across or down.
+    pub const MAX_SIDE: u64 = 1000;
+
+    /// Tallies the lines over their bounding box, or `None` if there are none.
+    pub fn new(lines: &[VentLine]) -> Option<Self> {
+        let ends = lines.iter().flat_map(|line| [&line.0, &line.1]);
+        let min = Coordinate {
+            x: ends.clone().map(|end| end.x).min()?,
+            y: ends.clone().map(|end| end.y).min()?,
+        };
+        let max = Coordinate {
+            x: ends.clone().map(|end| end.x).max()?,
+            y: ends.map(|end| end.y).max()?,
+        };
+
+        let (across, down) = (
+            max.x as u64 - min.x as u64 + 1,
+            max.y as u64 - min.y as u64 + 1,
+        );
+        let scale = across.max(down).div_ceil(Self::MAX_SIDE);
+        let (width, height) = (across.div_ceil(scale), down.div_ceil(scale));
+
+        let mut map = VentMap {
+            min,
+            max,
+            scale,
+            width: width as u32,
+            height: height as u32,
+            counts: vec![0; (width * height) as usize],
+        };
+
+        for line in lines {
+            map.trace(line);
+        }
+
+        Some(map)
+    }
+
+    /// Adds a line to the bins it passes through, a run of points at a time.
+    fn trace(&mut self, line: &VentLine) {
+        let (step_x, step_y) = line.step();
+        let steps = line.steps();
+        let scale = self.scale as i64;
+        let start = (
+            line.0.x as i64 - self.min.x as i64,
+            line.0.y as i64 - self.min.y as i64,
+        );
+
+        // How many more steps stay within the same bin along one axis
+        let remaining = |offset: i64, step: i64| match step.cmp(&0) {
+            Ordering::Greater => (scale - 1 - offset % scale) / step,
+            Ordering::Less => (offset % scale) / -step,
+            Ordering::Equal => i64::MAX,
+        };
+
+        let mut step = 0;
+        while step <= steps {
+            let (x, y) = (start.0 + step * step_x, start.1 + step * step_y);
+            let run = remaining(x, step_x)
+                .min(remaining(y, step_y))
+                .min(steps - step);
+
+            self.counts[(y / scale) as usize * self.width as usize + (x / scale) as usize] +=
+                run as u64 + 1;
+            step += run + 1;
+        }
+    }
+
+    pub fn min(&self) -> &Coordinate {
+        &self.min
+    }
+
+    pub fn max(&self) -> &Coordinate {
+        &self.max
+    }
+
+    /// How many points across and down each bin covers.
+    pub fn scale(&self) -> u64 {
+        self.scale
+    }
+
+    /// The number of bins across.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The number of bins down.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The count of the bin holding a point, which is zero outside the map.
+    pub fn count(&self, point: &Coordinate) -> u64 {
+        if (self.min.x..=self.max.x).contains(&point.x)
+            && (self.min.y..=self.max.y).contains(&point.y)
+        {
+            let column = (point.x - self.min.x) as u64 / self.scale;
+            let row = (point.y - self.min.y) as u64 / self.scale;
+            self.counts[(row * self.width as u64 + column) as usize]
+        } else {
+            0
+        }
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[u64]> {
+        self.counts.chunks(self.width as usize)
+    }
+
+    fn peak(&self) -> u128 {
+        self.counts.iter().copied().max().unwrap_or(0).max(1) as u128
+    }
+
+    /// Draws the map like the puzzle text does, with `.` where there are no
+    /// lines and the count elsewhere, or `+` for more than nine.
+    pub fn ascii(&self) -> String {
+        self.rows()
+            .map(|row| {
+                row.iter()
+                    .map(|count| match count {
+                        0 => '.',
+                        1..=9 => char::from_digit(*count as u32, 10).unwrap(),
+                        _ => '+',
+                    })
+                    .collect::<String>()
+            })
+            .join("\n")
+    }
+
+    /// Writes the map as a binary greyscale PGM, brighter where more lines meet.
+    pub fn write_pgm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let peak = self.peak();
+
+        write!(out, "P5\n{} {}\n255\n", self.width, self.height)?;
+        let pixels: Vec<u8> = self
+            .counts
+            .iter()
+            .map(|count| (*count as u128 * 255 / peak) as u8)
+            .collect();
+        out.write_all(&pixels)
+    }
+
+    /// Writes the map as a binary PPM heatmap, running from black through red
+    /// and yellow to white as more lines meet.
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let peak = self.peak();
+
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let pixels: Vec<u8> = self
+            .counts
+            .iter()
+            .flat_map(|count| {
+                let heat = *count as u128 * 765 / peak;
+                [heat, heat.saturating_sub(255), heat.saturating_sub(510)]
+                    .map(|channel| channel.min(255) as u8)
+            })
+            .collect();
+        out.write_all(&pixels)
+    }
+
+    /// Writes the map as text if it is small enough to read point by point,
+    /// and as a heatmap otherwise.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.scale == 1 && self.width <= Self::ASCII_LIMIT && self.height <= Self::ASCII_LIMIT {
+            writeln!(out, "{}", self.ascii())
+        } else {
+            self.write_ppm(out)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -164,4 +638,170 @@ mod tests {
     fn sample2() {
         assert_eq!(part2(&generator(SAMPLE)), 12);
     }
+
+    #[test]
+    fn counted1() {
+        assert_eq!(part1_counted(&generator(SAMPLE)), 5);
+    }
+
+    #[test]
+    fn counted2() {
+        assert_eq!(part2_counted(&generator(SAMPLE)), 12);
+    }
+
+    // A tangle of lines through a small field, in every orientation
+    fn tangle() -> Vec<VentLine> {
+        let input: String = (0..60u32)
+            .map(|i| {
+                let (a, b) = ((i * 7) % 23, (i * 11) % 19);
+                match i % 4 {
+                    0 => format!("{},{} -> {},{}\n", a, b, a, (b + i) % 23),
+                    1 => format!("{},{} -> {},{}\n", a, b, (a + i) % 29, b),
+                    2 => format!("{},{} -> {},{}\n", a, b, a + b, 0),
+                    _ => format!("{},{} -> {},{}\n", a, b, a + i % 7, b + i % 7),
+                }
+            })
+            .collect();
+
+        generator(&input)
+    }
+
+    #[test]
+    fn counted_matches_filtering() {
+        let lines = tangle();
+        let all: Vec<&VentLine> = lines.iter().collect();
+
+        assert_eq!(part1_counted(&lines), part1(&lines));
+        assert_eq!(part2_counted(&lines), part2(&lines));
+        assert_eq!(overlaps_sparse(&all), part2(&lines));
+    }
+
+    #[test]
+    fn analytic1() {
+        assert_eq!(part1_analytic(&generator(SAMPLE)), 5);
+    }
+
+    #[test]
+    fn analytic2() {
+        assert_eq!(part2_analytic(&generator(SAMPLE)), 12);
+    }
+
+    #[test]
+    fn analytic_matches_counted() {
+        let lines = tangle();
+
+        assert_eq!(part1_analytic(&lines), part1_counted(&lines));
+        assert_eq!(part2_analytic(&lines), part2_counted(&lines));
+    }
+
+    #[test]
+    fn analytic_long_lines() {
+        let lines = generator(
+            "0,0 -> 90000,90000
+50000,50000 -> 100000,100000
+0,100000 -> 100000,0
+0,50000 -> 100000,50000
+99999,50000 -> 20000,50000
+50000,0 -> 50000,100000
+49999,50001 -> 50001,49999
+7,7 -> 7,7",
+        );
+
+        assert_eq!(part2_analytic(&lines), part2_counted(&lines));
+    }
+
+    #[test]
+    fn analytic_steep_lines() {
+        let lines = generator("0,0 -> 2,6\n1,0 -> 1,9\n0,3 -> 4,1\n2,6 -> 4,0\n0,0 -> 4,4");
+
+        assert_eq!(part2_analytic(&lines), part2(&lines));
+        assert_eq!(part2_analytic(&lines), 6);
+    }
+
+    #[test]
+    fn ascii() {
+        let map = VentMap::new(&generator(SAMPLE)).unwrap();
+
+        assert_eq!(
+            map.ascii(),
+            "1.1....11.
+.111...2..
+..2.1.111.
+...1.2.2..
+.112313211
+...1.2....
+..1...1...
+.1.....1..
+1.......1.
+222111...."
+        );
+        assert_eq!(map.count(&Coordinate { x: 4, y: 4 }), 3);
+        assert_eq!(map.count(&Coordinate { x: 40, y: 4 }), 0);
+    }
+
+    #[test]
+    fn images() {
+        let map = VentMap::new(&generator("2,3 -> 4,3\n3,2 -> 3,4")).unwrap();
+        assert_eq!(
+            (map.min(), map.width(), map.height()),
+            (&Coordinate { x: 2, y: 2 }, 3, 3)
+        );
+
+        let mut pgm = Vec::new();
+        map.write_pgm(&mut pgm).unwrap();
+        assert_eq!(
+            pgm,
+            [
+                b"P5\n3 3\n255\n".as_slice(),
+                &[0, 127, 0, 127, 255, 127, 0, 127, 0]
+            ]
+            .concat()
+        );
+
+        let mut ppm = Vec::new();
+        map.write_ppm(&mut ppm).unwrap();
+        assert_eq!(&ppm[..11], b"P6\n3 3\n255\n");
+        assert_eq!(&ppm[11..17], &[0, 0, 0, 255, 127, 0]);
+        assert_eq!(&ppm[23..26], &[255, 255, 255]);
+
+        let mut text = Vec::new();
+        map.write(&mut text).unwrap();
+        assert_eq!(text, b".1.\n121\n.1.\n");
+    }
+
+    #[test]
+    fn binned_images() {
+        let map = VentMap::new(&generator("0,0 -> 4294967295,0")).unwrap();
+        assert_eq!((map.scale(), map.width(), map.height()), (4294968, 1000, 1));
+        assert_eq!(map.counts.iter().sum::<u64>(), 1 << 32);
+        assert_eq!(
+            map.count(&Coordinate {
+                x: 4294967295,
+                y: 0
+            }),
+            4294967295 % 4294968 + 1
+        );
+
+        let lines = generator(
+            "0,0 -> 90000,90000
+0,100000 -> 100000,0
+99999,50000 -> 20000,50000
+49999,50001 -> 50001,49999",
+        );
+        let map = VentMap::new(&lines).unwrap();
+        assert_eq!((map.scale(), map.width(), map.height()), (101, 991, 991));
+        assert_eq!(
+            map.counts.iter().sum::<u64>(),
+            lines.iter().map(|line| line.points().len() as u64).sum()
+        );
+        assert_eq!(
+            map.count(&Coordinate { x: 50000, y: 50000 }),
+            101 + 11 + 101 + 3
+        );
+
+        let mut ppm = Vec::new();
+        map.write(&mut ppm).unwrap();
+        assert!(ppm.starts_with(b"P6\n991 991\n255\n"));
+        assert_eq!(ppm.len(), 15 + 991 * 991 * 3);
+    }
 }